@@ -1,8 +1,15 @@
-use clap::{Args, Parser, Subcommand};
-use color_eyre::eyre::{ContextCompat, Result, WrapErr};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{eyre, ContextCompat, Result, WrapErr};
 use itertools::Itertools;
 use std::{path::PathBuf, str::FromStr};
-use video_scrubber_core::opencv::core::Rect;
+use video_scrubber_core::{
+	detect::{features::KeypointAlgorithm, phash::HashAlgorithm},
+	fixup::{ColorMode, Step},
+	frame::cpu::MatchMethod,
+	opencv::core::Rect,
+	scores::ScoreFormat,
+	segments::{FrameSpan, Padding, SegmentMode},
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, propagate_version = true)]
@@ -18,38 +25,203 @@ pub enum CliSubcommands {
 	Select(SelectArgs),
 }
 
+/// How frames are classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DetectorKind {
+	/// Template matching on the preprocessed intensities.
+	Template,
+	/// Template matching on edge maps of the templates and frames.
+	Edges,
+	/// Keypoint matching with homography verification, for overlays that are
+	/// rotated, scaled, or warped.
+	Features,
+	/// Perceptual hashes of whole frames, for full-screen slates and title
+	/// cards.
+	Hash,
+	/// Black frames, without any templates.
+	Black,
+	/// Frozen frames that barely differ from the one before, without any
+	/// templates.
+	Frozen,
+}
+
+impl DetectorKind {
+	/// Whether the detector needs at least one positive template.
+	pub fn needs_templates(&self) -> bool {
+		!matches!(self, Self::Black | Self::Frozen)
+	}
+}
+
 #[derive(Args)]
 pub struct ScrubArgs {
 	/// The input video file.
 	#[arg(short, long)]
 	pub input: PathBuf,
-	/// The template image file.
-	#[arg(short = 'p',  required = true, num_args = 1..)]
+	/// The template image files, directories, or manifests (.toml/.json).
+	/// Required unless a template-free detector is used.
+	#[arg(short = 'p', num_args = 1..)]
 	pub pos_templates: Vec<PathBuf>,
-	/// The negative template image files.
+	/// The negative template image files, directories, or manifests.
 	#[arg(short = 'n', allow_hyphen_values = true)]
 	pub neg_templates: Vec<PathBuf>,
 	/// The file to output to.
 	#[arg(short, default_value = "output.mkv")]
 	pub output: PathBuf,
-	/// The minimum match threshold (0-1).
-	#[arg(short = 'm', default_value = "0.7")]
-	pub pos_threshold: f64,
-	/// The minimum negative match threshold (0-1).
-	#[arg(short = 'x', default_value = "0.7")]
-	pub neg_threshold: f64,
+	/// The minimum match threshold, unless a manifest overrides it. This is
+	/// also the threshold a run of frames to remove has to enter with.
+	/// Defaults to 0.7 for ccoeff_normed, 0.97 for ccorr_normed, and 0.9 for
+	/// sqdiff_normed. Each method has its own scale, so thresholds that
+	/// unrelated content would reach with the chosen method are rejected.
+	#[arg(short = 'm')]
+	pub pos_threshold: Option<f64>,
+	/// Once a run of frames to remove has started, keep it going through
+	/// frames scoring at least this, on the same scale as -m, so it only ends
+	/// on a clear drop. Used by the template and edges detectors, and can't be
+	/// combined with manifest templates that pick another method.
+	#[arg(long)]
+	pub exit_threshold: Option<f64>,
+	/// Ignore runs of matched frames shorter than this many frames, or seconds
+	/// with an "s" suffix (e.g. 0.5s).
+	#[arg(long, default_value = "1", value_parser = FrameSpan::from_str)]
+	pub min_run: FrameSpan,
+	/// Join runs of matched frames separated by at most this many frames, or
+	/// seconds with an "s" suffix.
+	#[arg(long, default_value = "0", value_parser = FrameSpan::from_str)]
+	pub max_gap: FrameSpan,
+	/// The minimum negative match threshold, unless a manifest overrides it.
+	/// Defaults to the same as -m.
+	#[arg(short = 'x')]
+	pub neg_threshold: Option<f64>,
+	/// Whether to remove the matched parts of the video, or keep only them
+	/// (remove or keep), e.g. to compile a highlights reel.
+	#[arg(long, default_value = "remove", value_parser = SegmentMode::from_str)]
+	pub mode: SegmentMode,
 	/// How many seconds to pad out removal ranges with, just to be sure.
 	#[arg(short = 'f', long, default_value = "1.0")]
 	pub padding: f64,
+	/// The padding before removal ranges, in frames, or seconds with an "s"
+	/// suffix. Overrides --padding.
+	#[arg(long, value_parser = FrameSpan::from_str)]
+	pub lead_in: Option<FrameSpan>,
+	/// The padding after removal ranges, in frames, or seconds with an "s"
+	/// suffix. Overrides --padding.
+	#[arg(long, value_parser = FrameSpan::from_str)]
+	pub lead_out: Option<FrameSpan>,
+	/// The padding of ranges matched by the template with the given label,
+	/// as LABEL=LEAD_IN:LEAD_OUT (e.g. ad=0.2s:2s) or LABEL=PADDING.
+	#[arg(long, value_parser = parse_label_padding)]
+	pub label_padding: Vec<(String, Padding)>,
+	/// Snap the edges of removal ranges to the nearest scene cut within this
+	/// many seconds, falling back to the padding when there is none.
+	#[arg(long)]
+	pub snap_window: Option<f64>,
+	/// The mean luma difference (0-255) between two consecutive frames above
+	/// which the second one counts as a scene cut.
+	#[arg(long, default_value = "30")]
+	pub scene_threshold: f64,
 	/// The bounds of the region of interest (x,y,width,height).
 	#[arg(short, long, value_parser = parse_rect)]
 	pub bounds: Option<Rect>,
+	/// How frames are classified.
+	#[arg(long, value_enum, default_value = "template")]
+	pub detector: DetectorKind,
+	/// The edge step used by the edges detector, either canny:LOW:HIGH or
+	/// sobel:SIZE.
+	#[arg(long, default_value = "sobel:3", value_parser = parse_edge_step)]
+	pub edge_step: Step,
+	/// The keypoint algorithm used by the features detector (orb or akaze).
+	#[arg(long, default_value = "orb", value_parser = KeypointAlgorithm::from_str)]
+	pub keypoints: KeypointAlgorithm,
+	/// How many keypoints must agree on a template's position for the
+	/// features detector to count it as found.
+	#[arg(long, default_value = "12")]
+	pub min_inliers: usize,
+	/// Once a run of frames to remove has started, the features detector
+	/// keeps it going through frames with at least this many inliers.
+	#[arg(long)]
+	pub exit_inliers: Option<usize>,
+	/// The perceptual hash used by the hash detector (phash or dhash).
+	#[arg(long, default_value = "phash", value_parser = HashAlgorithm::from_str)]
+	pub hash: HashAlgorithm,
+	/// The maximum Hamming distance (0-64) between a frame's hash and a
+	/// template's hash for the hash detector to count it as a match.
+	#[arg(long, default_value = "10")]
+	pub max_distance: u32,
+	/// Once a run of frames to remove has started, the hash detector keeps
+	/// it going through frames within this Hamming distance of a template.
+	#[arg(long)]
+	pub exit_distance: Option<u32>,
+	/// The highest average luma (0-255) a frame may have for the black
+	/// detector to count it as black.
+	#[arg(long, default_value = "16")]
+	pub black_threshold: f64,
+	/// The highest luma (0-255) any part of a frame may have for the black
+	/// detector to count it as black.
+	#[arg(long)]
+	pub black_max: Option<f64>,
+	/// The highest mean luma difference (0-255) to the previous frame for the
+	/// frozen detector to count a frame as frozen.
+	#[arg(long, default_value = "0.5")]
+	pub freeze_threshold: f64,
+	/// The template matching method (ccoeff_normed, ccorr_normed, or
+	/// sqdiff_normed), unless a manifest overrides it.
+	#[arg(long, default_value = "ccoeff_normed", value_parser = MatchMethod::from_str)]
+	pub method: MatchMethod,
+	/// The colour space to match in (grey, bgr, or hsv).
+	#[arg(short = 'c', long, default_value = "grey", value_parser = ColorMode::from_str)]
+	pub color: ColorMode,
+	/// The preprocessing steps applied to both templates and frames, in order
+	/// (comma-separated, or "none"). Available steps are blur:SIZE,
+	/// clahe:CLIP:TILE, canny:LOW:HIGH, sobel:SIZE, and normalize.
+	#[arg(long, default_value = "blur:5,normalize")]
+	pub preprocess: String,
+	/// The scale factors to try every template at (comma-separated).
+	#[arg(long, value_delimiter = ',', default_value = "1.0")]
+	pub scales: Vec<f64>,
+	/// The height of the video the templates were cut from. Scale factors
+	/// become relative to the ratio between the input video's height and this.
+	#[arg(long)]
+	pub template_height: Option<i32>,
 	/// How many threads to use. Defaults to the amount of logical cores.
 	#[arg(short = 'j', long)]
 	pub threads: Option<usize>,
+	/// How many decoded frames may wait for a worker thread at once.
+	#[arg(long, default_value = "64", conflicts_with = "queue_memory")]
+	pub queue_depth: usize,
+	/// Limit the decoded frame queue to this many megabytes instead of a fixed
+	/// amount of frames, up to 4096 frames.
+	#[arg(long)]
+	pub queue_memory: Option<usize>,
+	/// Also remove audio quieter than this many dBFS (e.g. -50).
+	#[arg(long, allow_hyphen_values = true)]
+	pub silence_db: Option<f64>,
+	/// How many seconds audio has to stay quiet to count as silence.
+	#[arg(long, default_value = "0.5")]
+	pub silence_duration: f64,
+	/// Also remove every occurrence of these reference audio clips, such as
+	/// ad break jingles.
+	#[arg(long)]
+	pub jingle: Vec<PathBuf>,
+	/// The minimum correlation (0-1) between a jingle and the audio for it
+	/// to count as found.
+	#[arg(long, default_value = "0.8")]
+	pub jingle_threshold: f64,
 	/// The ffmpeg options to force.
 	#[arg(short = 'o', long)]
 	pub ffmpeg_opts: Option<String>,
+	/// Write the scores of every scanned frame to this file. The template and
+	/// edges detectors then score every template on every frame, which is
+	/// slower.
+	#[arg(long)]
+	pub scores: Option<PathBuf>,
+	/// The format to write scores in (csv or jsonl). Guessed from the file
+	/// extension by default.
+	#[arg(long, value_parser = ScoreFormat::from_str)]
+	pub scores_format: Option<ScoreFormat>,
+	/// Where to write the indices of the frames matched so far if the scan is
+	/// interrupted.
+	#[arg(long)]
+	pub partial_output: Option<PathBuf>,
 }
 
 #[derive(Args)]
@@ -58,23 +230,37 @@ pub struct TestArgs {
 	/// The input image files.
 	#[arg(short, long, required = true, num_args = 1..)]
 	pub input: Vec<PathBuf>,
-	/// The template image file.
+	/// The template image files, directories, or manifests (.toml/.json).
 	#[arg(short, long, required = true, num_args = 1..)]
 	pub template: Vec<PathBuf>,
-	/// The negative template image files.
+	/// The negative template image files, directories, or manifests.
 	#[arg(short, long)]
 	pub negative_template: Vec<PathBuf>,
-	/// The minimum match threshold (0-1).
-	#[arg(short = 'm', long, default_value = "0.7")]
-	pub threshold: f64,
-	/// The minimum negative match threshold (0-1).
-	#[arg(short = 'x', long, default_value = "0.6")]
-	pub negative_threshold: f64,
+	/// The minimum match threshold, unless a manifest overrides it. Defaults
+	/// to the method's default threshold.
+	#[arg(short = 'm', long)]
+	pub threshold: Option<f64>,
+	/// The minimum negative match threshold, unless a manifest overrides it.
+	/// Defaults to the method's default threshold.
+	#[arg(short = 'x', long)]
+	pub negative_threshold: Option<f64>,
 	/// The bounds of the region of interest (x,y,width,height).
 	#[arg(short, long, value_parser = parse_rect)]
 	pub bounds: Option<Rect>,
+	/// The template matching method (ccoeff_normed, ccorr_normed, or
+	/// sqdiff_normed), unless a manifest overrides it.
+	#[arg(long, default_value = "ccoeff_normed", value_parser = MatchMethod::from_str)]
+	pub method: MatchMethod,
+	/// The colour space to match in (grey, bgr, or hsv).
+	#[arg(short = 'c', long, default_value = "grey", value_parser = ColorMode::from_str)]
+	pub color: ColorMode,
+	/// The preprocessing steps applied to both templates and images, in order
+	/// (comma-separated, or "none").
+	#[arg(long, default_value = "blur:5,normalize")]
+	pub preprocess: String,
+	/// Print the results as JSON instead of a table.
 	#[arg(long)]
-	pub color: bool,
+	pub json: bool,
 }
 
 #[derive(Args)]
@@ -84,6 +270,22 @@ pub struct SelectArgs {
 	pub input: PathBuf,
 }
 
+fn parse_edge_step(arg: &str) -> Result<Step> {
+	match Step::from_str(arg)? {
+		step @ (Step::Canny { .. } | Step::Sobel(_)) => Ok(step),
+		_ => Err(eyre!("edge step must be canny or sobel, got '{arg}'")),
+	}
+}
+
+fn parse_label_padding(arg: &str) -> Result<(String, Padding)> {
+	let (label, padding) = arg
+		.split_once('=')
+		.context("label padding should be formatted as label=lead_in:lead_out")?;
+	let padding = Padding::from_str(padding)
+		.wrap_err_with(|| format!("invalid padding for label '{label}'"))?;
+	Ok((label.trim().to_owned(), padding))
+}
+
 fn parse_rect(arg: &str) -> Result<Rect> {
 	let (x, y, width, height) = arg
 		.split(',')
@@ -1,6 +1,7 @@
 pub mod cmd;
 pub mod scrub;
 pub mod select;
+pub mod test;
 
 use self::cmd::{CliArgs, CliSubcommands};
 use clap::Parser;
@@ -14,7 +15,7 @@ fn main() -> Result<()> {
 	let args = CliArgs::parse();
 	match args.command {
 		CliSubcommands::Scrub(args) => scrub::scrub(args),
-		CliSubcommands::Test(_args) => todo!(),
+		CliSubcommands::Test(args) => test::test(args),
 		CliSubcommands::Select(args) => select::select(args),
 	}
 }
@@ -1,137 +1,292 @@
-use crate::cmd::ScrubArgs;
-use color_eyre::eyre::{ContextCompat, Result, WrapErr};
-use crossbeam_channel::unbounded;
+use crate::cmd::{DetectorKind, ScrubArgs};
+use color_eyre::eyre::{eyre, Result, WrapErr};
 use indicatif::{HumanCount, ProgressBar, ProgressState, ProgressStyle};
-use parking_lot::Mutex;
-use std::{
-	fmt::Write,
-	sync::{
-		atomic::{AtomicBool, Ordering},
-		Arc,
-	},
-	thread,
-};
-use thread_priority::{ThreadBuilderExt, ThreadPriority};
+use std::{fmt::Write, fs, path::Path};
 use video_scrubber_core::{
-	frame::{self, Frame},
-	opencv::{
-		imgcodecs::IMREAD_GRAYSCALE,
-		videoio::{VideoCapture, VideoCaptureTraitConst, CAP_FFMPEG, CAP_PROP_FRAME_COUNT},
+	audio::{self, AudioAnalyzer, AudioClip},
+	cancel::CancellationToken,
+	detect::{
+		blank::{BlackFrameDetector, FrozenFrameDetector},
+		features::FeatureDetector,
+		phash::HashDetector,
+		Detector,
+	},
+	fixup::{self, Preprocessor},
+	frame::{
+		cpu::{EdgeDetector, TemplateDetector},
+		QueueLimit,
 	},
-	segments, templates, video, FRAMES_PROCESSED,
+	progress::{Phase, ProgressEvent, ProgressObserver},
+	scores::{self, ScoreFormat},
+	scrub::Scrubber,
+	segments::{FrameSpan, Padding, RunRules, SegmentMode},
+	templates::{self, Template},
+	timeline::TimeRange,
 };
 
-pub static DONE_PROCESSING: AtomicBool = AtomicBool::new(false);
+/// Shows the scanning progress of a single job as an indicatif progress bar.
+struct ProgressBarObserver {
+	progress_bar: ProgressBar,
+}
 
-pub fn scrub(args: ScrubArgs) -> Result<()> {
-	let (frame_sender, frame_receiver) = unbounded::<Frame>();
-	let (result_sender, result_receiver) = unbounded::<usize>();
-	let exceeding_frames = Arc::new(Mutex::new(Vec::<usize>::new()));
-
-	if let Some(opts) = &args.ffmpeg_opts {
-		std::env::set_var("OPENCV_FFMPEG_CAPTURE_OPTIONS", opts);
-	}
-
-	// Read in the video file
-	let mut capture = VideoCapture::from_file(
-		args.input
-			.to_str()
-			.wrap_err("invalid input path cannot be represented as a str")?,
-		CAP_FFMPEG,
-	)
-	.wrap_err_with(|| format!("failed to read video from {}", args.input.display()))?;
-
-	let total_frames = capture
-		.get(CAP_PROP_FRAME_COUNT)
-		.wrap_err("failed to get frame count property from video")?;
-
-	let exceeding_frames_clone = exceeding_frames.clone();
-	thread::spawn(move || {
-		for frame in result_receiver {
-			exceeding_frames_clone.lock().push(frame);
+impl ProgressBarObserver {
+	fn new(total_frames: u64) -> Self {
+		let progress_bar = ProgressBar::new(total_frames).with_style(
+			ProgressStyle::with_template(
+				"[{elapsed}] {wide_bar:.green/red} {pos}/{len} frames ({per_sec}, ETA: {eta})",
+			)
+			.unwrap()
+			.with_key("pos", |state: &ProgressState, w: &mut dyn Write| {
+				write!(w, "{}", HumanCount(state.pos())).unwrap()
+			})
+			.with_key("len", |state: &ProgressState, w: &mut dyn Write| {
+				write!(w, "{}", HumanCount(state.len().unwrap())).unwrap()
+			})
+			.with_key("per_sec", |state: &ProgressState, w: &mut dyn Write| {
+				write!(w, "{:.1} fps", state.per_sec().round() as u64).unwrap()
+			}),
+		);
+		Self { progress_bar }
+	}
+}
+
+impl ProgressObserver for ProgressBarObserver {
+	fn on_progress(&self, event: ProgressEvent) {
+		match event {
+			ProgressEvent::FrameProcessed { .. } => self.progress_bar.inc(1),
+			ProgressEvent::Phase(Phase::Segmenting) => {
+				self.progress_bar.finish();
+				println!("finished scanning video");
+			}
+			ProgressEvent::Phase(Phase::Splicing) => println!("splicing video"),
+			ProgressEvent::Phase(Phase::Finished) => println!("finished splicing video"),
+			_ => {}
+		}
+	}
+}
+
+fn build_detector(
+	args: &ScrubArgs,
+	pos_templates: Vec<Template>,
+	neg_templates: Vec<Template>,
+) -> Box<dyn Detector> {
+	let pos_threshold = args
+		.pos_threshold
+		.unwrap_or_else(|| args.method.default_threshold());
+	let template_detector = |pos_templates, neg_templates| {
+		TemplateDetector::new(pos_templates, neg_templates)
+			.pos_threshold(Some(pos_threshold))
+			.neg_threshold(Some(args.neg_threshold.unwrap_or(pos_threshold)))
+			.exit_threshold(args.exit_threshold)
+			.score_all(args.scores.is_some())
+			.bounds(args.bounds)
+			.method(args.method)
+			.scales(args.scales.clone())
+			.template_height(args.template_height)
+	};
+	match args.detector {
+		DetectorKind::Template => Box::new(template_detector(pos_templates, neg_templates)),
+		DetectorKind::Edges => Box::new(EdgeDetector::new(
+			template_detector(pos_templates, neg_templates),
+			args.edge_step,
+		)),
+		DetectorKind::Features => Box::new(
+			FeatureDetector::new(pos_templates, neg_templates)
+				.algorithm(args.keypoints)
+				.min_inliers(args.min_inliers)
+				.exit_inliers(args.exit_inliers)
+				.bounds(args.bounds),
+		),
+		DetectorKind::Hash => Box::new(
+			HashDetector::new(pos_templates, neg_templates)
+				.algorithm(args.hash)
+				.max_distance(args.max_distance)
+				.exit_distance(args.exit_distance)
+				.color_mode(args.color),
+		),
+		DetectorKind::Black => Box::new(
+			BlackFrameDetector::new()
+				.mean_threshold(args.black_threshold)
+				.max_threshold(args.black_max),
+		),
+		DetectorKind::Frozen => {
+			Box::new(FrozenFrameDetector::new().threshold(args.freeze_threshold))
 		}
-	});
+	}
+}
 
-	let pos_templates = templates::load_multi(&args.pos_templates, IMREAD_GRAYSCALE)
+pub fn scrub(args: ScrubArgs) -> Result<()> {
+	if args.detector.needs_templates() && args.pos_templates.is_empty() {
+		return Err(eyre!(
+			"at least one positive template (-p) is required for this detector"
+		));
+	}
+
+	let preprocessor = Preprocessor {
+		color_mode: args.color,
+		steps: fixup::parse_steps(&args.preprocess)
+			.wrap_err("failed to parse preprocessing steps")?,
+	};
+
+	let pos_templates = templates::load_multi(&args.pos_templates, &preprocessor)
 		.wrap_err("failed to parse positive templates")?;
-	let neg_templates = templates::load_multi(&args.neg_templates, IMREAD_GRAYSCALE)
+	let neg_templates = templates::load_multi(&args.neg_templates, &preprocessor)
 		.wrap_err("failed to parse negative templates")?;
+	if let Some((label, _)) = args.label_padding.iter().find(|(label, _)| {
+		!pos_templates
+			.iter()
+			.any(|template| &template.label == label)
+	}) {
+		return Err(eyre!("no positive template has the padded label '{label}'"));
+	}
 
-	frame::cpu::spawn_threads(
-		args.threads,
-		args.bounds,
-		&pos_templates,
-		&neg_templates,
-		Some(args.pos_threshold),
-		Some(args.neg_threshold),
-		frame_receiver.clone(),
-		result_sender.clone(),
-	)
-	.wrap_err("failed to setup cpu worker threads")?;
-
-	let progress_thread = thread::Builder::new()
-		.name("frame progress thread".to_owned())
-		.spawn_with_priority(ThreadPriority::Min, move |_| {
-			let total_frames = total_frames.round() as u64;
-			let progress_bar = ProgressBar::new(total_frames).with_style(
-				ProgressStyle::with_template(
-					"[{elapsed}] {wide_bar:.green/red} {pos}/{len} frames ({per_sec}, ETA: {eta})",
-				)
-				.unwrap()
-				.with_key("pos", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{}", HumanCount(state.pos())).unwrap()
-				})
-				.with_key("len", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{}", HumanCount(state.len().unwrap())).unwrap()
-				})
-				.with_key("per_sec", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{:.1} fps", state.per_sec().round() as u64).unwrap()
-				}),
-			);
-			while !DONE_PROCESSING.load(Ordering::Relaxed) {
-				let frames_processed = FRAMES_PROCESSED.load(Ordering::Relaxed) as u64;
-				if frames_processed >= total_frames {
-					DONE_PROCESSING.store(true, Ordering::Relaxed);
-					break;
-				}
-				progress_bar.set_position(frames_processed);
-				std::thread::yield_now();
-			}
-			progress_bar.finish();
+	let detector = build_detector(&args, pos_templates, neg_templates);
+
+	let jingles = args
+		.jingle
+		.iter()
+		.map(|path| AudioClip::load(path))
+		.collect::<Result<Vec<_>>>()
+		.wrap_err("failed to load jingles")?;
+	let mut audio = AudioAnalyzer::new()
+		.clips(jingles)
+		.clip_threshold(args.jingle_threshold);
+	if let Some(threshold_db) = args.silence_db {
+		audio = audio.silence(threshold_db, args.silence_duration);
+	}
+
+	let padding = FrameSpan::Seconds(args.padding);
+	let mut scrubber = Scrubber::new(detector)
+		.padding(Padding::new(
+			args.lead_in.unwrap_or(padding),
+			args.lead_out.unwrap_or(padding),
+		))
+		.scene_threshold(args.scene_threshold)
+		.snap_window(args.snap_window)
+		.mode(args.mode)
+		.run_rules(RunRules {
+			min_run: args.min_run,
+			max_gap: args.max_gap,
+		})
+		.audio(Some(audio))
+		.threads(args.threads)
+		.preprocessor(preprocessor)
+		.queue_limit(match args.queue_memory {
+			Some(megabytes) => QueueLimit::Megabytes(megabytes),
+			None => QueueLimit::Frames(args.queue_depth),
 		})
-		.wrap_err("failed to spawn progress bar thread")?;
+		.ffmpeg_options(args.ffmpeg_opts);
+	for (label, padding) in args.label_padding.iter().cloned() {
+		scrubber = scrubber.label_padding(label, padding);
+	}
 
-	frame::send_frames(&mut capture, frame_sender)
-		.wrap_err("failed to send frames to worker threads")?;
+	let job = scrubber
+		.job(&args.input)
+		.wrap_err_with(|| format!("failed to open {}", args.input.display()))?;
+	let progress = ProgressBarObserver::new(job.total_frames() as u64);
 
-	drop(frame_receiver);
-	drop(result_sender);
-	let _ = progress_thread.join();
-	println!("finished scanning video");
-	DONE_PROCESSING.store(true, Ordering::Relaxed);
+	let cancel = CancellationToken::new();
+	let ctrlc_cancel = cancel.clone();
+	ctrlc::set_handler(move || {
+		// A second interrupt means the user really wants out, now.
+		if ctrlc_cancel.is_cancelled() {
+			std::process::exit(130);
+		}
+		eprintln!("interrupted, stopping scan (press ctrl-c again to force quit)");
+		ctrlc_cancel.cancel();
+	})
+	.wrap_err("failed to set ctrl-c handler")?;
 
-	let mut exceeding_frames = exceeding_frames.lock();
-	exceeding_frames.sort(); // Sort the frames in ascending order
+	let mut job = job.with_observer(progress).with_cancellation_token(cancel);
+	let result = job.scan().wrap_err("failed to scan video")?;
 
-	let segments = segments::frames_to_segments(args.padding, &capture, &*exceeding_frames)
-		.wrap_err("failed to convert frames to time ranges")?;
+	// Scores are written even for cancelled scans, they're still useful for
+	// tuning thresholds.
+	if let Some(path) = &args.scores {
+		let format = args
+			.scores_format
+			.unwrap_or_else(|| ScoreFormat::from_path(path));
+		scores::write_scores(path, &result.scores, format)
+			.wrap_err_with(|| format!("failed to write scores to {}", path.display()))?;
+		println!(
+			"wrote {} frame scores to {}",
+			result.scores.len(),
+			path.display()
+		);
+	}
 
-	for (idx, (start, end)) in segments.iter().copied().enumerate() {
+	if result.cancelled {
+		println!(
+			"scan cancelled after {} frames, found {} exceeding frames so far",
+			result.stats.frames_scanned, result.stats.frames_matched
+		);
+		if let Some(path) = &args.partial_output {
+			write_matched_frames(path, &result.matched_frames).wrap_err_with(|| {
+				format!("failed to write partial results to {}", path.display())
+			})?;
+			println!("wrote partial results to {}", path.display());
+		}
+		return Ok(());
+	}
+
+	for (idx, range) in result.segments.iter().copied().enumerate() {
+		let (start, end) = result.timeline.range_seconds(range);
 		println!("segment #{idx}: {start:.1}s -> {end:.1}s");
 	}
 
-	let percentage_exceeding = (exceeding_frames.len() as f64 / total_frames) * 100.0;
 	println!(
 		"found {} (out of {}) exceeding frames ({:.2}%)",
-		exceeding_frames.len(),
-		total_frames,
-		percentage_exceeding
+		result.stats.frames_matched,
+		result.stats.frames_scanned,
+		result.stats.match_percentage()
+	);
+	if let Some(scale) = result.best_scale {
+		println!("strongest match was at template scale {scale:.2}");
+	}
+	let audio_seconds = |range: TimeRange| {
+		result
+			.timeline
+			.range_seconds(range.rescale(audio::TIME_BASE, result.timeline.time_base()))
+	};
+	for range in &result.audio.silences {
+		let (start, end) = audio_seconds(*range);
+		println!("silence: {start:.1}s -> {end:.1}s");
+	}
+	for (label, range) in &result.audio.clips {
+		let (start, end) = audio_seconds(*range);
+		println!("jingle {label}: {start:.1}s -> {end:.1}s");
+	}
+	if args.snap_window.is_some() {
+		println!("detected {} scene cuts", result.scene_cuts.len());
+	}
+
+	let queue = &result.stats.queue;
+	println!(
+		"frame queue: capacity {}, peak depth {}, mean depth {:.1}, decoder stalled {} times \
+		 ({:.1}s)",
+		queue.capacity,
+		queue.peak_depth,
+		queue.mean_depth,
+		queue.stalls,
+		queue.stall_time.as_secs_f64()
 	);
 
-	println!("splicing video");
-	video::splice_video(&args.input, &args.output, &segments)
-		.wrap_err("failed to splice segments into single video")?;
-	println!("finished splicing video");
+	if result.segments.is_empty() {
+		return Err(match args.mode {
+			SegmentMode::Remove => eyre!("every frame was removed, there is nothing to splice"),
+			SegmentMode::Keep => eyre!("no frames matched, there is nothing to keep"),
+		});
+	}
+	job.splice(&result, &args.output)?;
 
 	Ok(())
 }
+
+fn write_matched_frames(path: &Path, matched_frames: &[usize]) -> Result<()> {
+	let mut out = String::new();
+	for index in matched_frames {
+		writeln!(out, "{index}")?;
+	}
+	fs::write(path, out)?;
+	Ok(())
+}
@@ -0,0 +1,237 @@
+use crate::cmd::TestArgs;
+use color_eyre::eyre::{ContextCompat, Result, WrapErr};
+use serde_json::json;
+use std::path::Path;
+use video_scrubber_core::{
+	detect::Detection,
+	fixup::{self, Preprocessor},
+	frame::cpu::{self, MatchMethod, ScaledTemplate},
+	opencv::{
+		core::{Mat, Rect},
+		imgcodecs::{self, IMREAD_COLOR},
+	},
+	templates::{self, Template},
+};
+
+/// The score of a single image against a single template.
+struct TemplateScore {
+	label: String,
+	negative: bool,
+	score: Option<f64>,
+	threshold: f64,
+	detection: Detection,
+}
+
+impl TemplateScore {
+	fn passed(&self) -> bool {
+		self.score.is_some_and(|score| score >= self.threshold)
+	}
+}
+
+fn read_image(path: &Path, preprocessor: &Preprocessor) -> Result<Mat> {
+	let image = imgcodecs::imread(
+		path.to_str()
+			.wrap_err("invalid path cannot be represented as a str")?,
+		IMREAD_COLOR,
+	)
+	.wrap_err_with(|| format!("failed to read image from {}", path.display()))?;
+	fixup::fixup_frame_2(&image, preprocessor).wrap_err("failed to preprocess image")
+}
+
+/// Scores the image against a single template, on its own.
+fn score_template(
+	bounds: Option<&Rect>,
+	method: MatchMethod,
+	result: &mut Mat,
+	image: &Mat,
+	template: &ScaledTemplate,
+	negative: bool,
+	threshold: f64,
+) -> Result<TemplateScore> {
+	let threshold =
+		cpu::template_threshold(&template.template, method, Some(threshold)).unwrap_or(threshold);
+	let detection = cpu::process_frame(
+		bounds,
+		method,
+		result,
+		image,
+		std::slice::from_ref(template),
+		&[],
+		Some(threshold),
+		None,
+		None,
+		false,
+	)?;
+	Ok(TemplateScore {
+		label: template.template.label.clone(),
+		negative,
+		// No label means the template was larger than the image.
+		score: detection.pos_label.as_ref().map(|_| detection.pos_score),
+		threshold,
+		detection,
+	})
+}
+
+fn scale(templates: Vec<Template>) -> Result<Vec<ScaledTemplate>> {
+	cpu::scale_templates(&templates, &[1.0])
+}
+
+pub fn test(args: TestArgs) -> Result<()> {
+	let preprocessor = Preprocessor {
+		color_mode: args.color,
+		steps: fixup::parse_steps(&args.preprocess)
+			.wrap_err("failed to parse preprocessing steps")?,
+	};
+	let pos_templates = scale(
+		templates::load_multi(&args.template, &preprocessor)
+			.wrap_err("failed to read templates")?,
+	)?;
+	let neg_templates = scale(
+		templates::load_multi(&args.negative_template, &preprocessor)
+			.wrap_err("failed to read negative templates")?,
+	)?;
+
+	let threshold = args
+		.threshold
+		.unwrap_or_else(|| args.method.default_threshold());
+	let negative_threshold = args
+		.negative_threshold
+		.unwrap_or_else(|| args.method.default_threshold());
+	cpu::check_thresholds(&pos_templates, args.method, Some(threshold))?;
+	cpu::check_thresholds(&neg_templates, args.method, Some(negative_threshold))?;
+
+	let mut result = Mat::default();
+	let mut reports = Vec::with_capacity(args.input.len());
+	for input in &args.input {
+		let image = read_image(input, &preprocessor)
+			.wrap_err_with(|| format!("failed to read input image {}", input.display()))?;
+		let bounds = args.bounds.as_ref();
+
+		let mut scores = Vec::with_capacity(pos_templates.len() + neg_templates.len());
+		for template in &pos_templates {
+			scores.push(
+				score_template(
+					bounds,
+					args.method,
+					&mut result,
+					&image,
+					template,
+					false,
+					threshold,
+				)
+				.wrap_err_with(|| format!("failed to score {}", input.display()))?,
+			);
+		}
+		for template in &neg_templates {
+			scores.push(
+				score_template(
+					bounds,
+					args.method,
+					&mut result,
+					&image,
+					template,
+					true,
+					negative_threshold,
+				)
+				.wrap_err_with(|| format!("failed to score {}", input.display()))?,
+			);
+		}
+		let verdict = cpu::process_frame(
+			bounds,
+			args.method,
+			&mut result,
+			&image,
+			&pos_templates,
+			&neg_templates,
+			Some(threshold),
+			Some(negative_threshold),
+			None,
+			false,
+		)
+		.wrap_err_with(|| format!("failed to score {}", input.display()))?;
+		reports.push((input.as_path(), scores, verdict));
+	}
+
+	if args.json {
+		print_json(&reports)
+	} else {
+		print_table(&reports);
+		Ok(())
+	}
+}
+
+type Report<'a> = (&'a Path, Vec<TemplateScore>, Detection);
+
+fn print_table(reports: &[Report]) {
+	let label_width = reports
+		.iter()
+		.flat_map(|(_, scores, _)| scores.iter().map(|score| score.label.len()))
+		.chain(["template".len()])
+		.max()
+		.unwrap_or_default();
+	for (input, scores, verdict) in reports {
+		println!("{}", input.display());
+		println!(
+			"  {:<label_width$}  kind  score  threshold  result",
+			"template"
+		);
+		for score in scores {
+			let value = match score.score {
+				Some(value) => format!("{value:.3}"),
+				None => "-".to_owned(),
+			};
+			let result = match (score.negative, score.passed()) {
+				(false, true) => "match",
+				(true, true) => "veto",
+				(_, false) => "",
+			};
+			println!(
+				"  {:<label_width$}  {:<4}  {value:>5}  {:>9.3}  {result}",
+				score.label,
+				if score.negative { "neg" } else { "pos" },
+				score.threshold,
+			);
+		}
+		println!(
+			"  => {} (pos {:.3}, neg {:.3})",
+			if verdict.matched {
+				"MATCHED"
+			} else {
+				"not matched"
+			},
+			verdict.pos_score,
+			verdict.neg_score
+		);
+	}
+}
+
+fn print_json(reports: &[Report]) -> Result<()> {
+	let reports = reports
+		.iter()
+		.map(|(input, scores, verdict)| {
+			json!({
+				"image": input.display().to_string(),
+				"matched": verdict.matched,
+				"pos_score": verdict.pos_score,
+				"neg_score": verdict.neg_score,
+				"templates": scores
+					.iter()
+					.map(|score| json!({
+						"label": score.label,
+						"negative": score.negative,
+						"score": score.score,
+						"threshold": score.threshold,
+						"passed": score.passed(),
+						"x": score.detection.location.map(|location| location.x),
+						"y": score.detection.location.map(|location| location.y),
+					}))
+					.collect::<Vec<_>>(),
+			})
+		})
+		.collect::<Vec<_>>();
+	println!(
+		"{}",
+		serde_json::to_string_pretty(&reports).wrap_err("failed to serialize results")?
+	);
+	Ok(())
+}
@@ -0,0 +1,442 @@
+use crate::{cancel::CancellationToken, timeline::TimeRange};
+use color_eyre::eyre::{ContextCompat, Result, WrapErr};
+use ffmpeg::{Rational, Rescale};
+use ffmpeg_next as ffmpeg;
+use std::path::Path;
+
+/// Audio is downmixed and resampled to this rate before being analysed.
+const SAMPLE_RATE: u32 = 8000;
+/// The time base of every [`TimeRange`] found in audio, one tick per sample.
+pub const TIME_BASE: Rational = Rational(1, SAMPLE_RATE as i32);
+/// The length of a single analysis window, in samples (10ms).
+const WINDOW: usize = (SAMPLE_RATE / 100) as usize;
+/// Keeps the logarithm of digital silence finite.
+const ENERGY_FLOOR: f64 = 1e-10;
+
+/// The loudness of a window of samples, in dB relative to full scale.
+fn loudness(window: &[f32]) -> f64 {
+	let energy = window
+		.iter()
+		.map(|&sample| f64::from(sample) * f64::from(sample))
+		.sum::<f64>()
+		/ window.len() as f64;
+	10.0 * energy.max(ENERGY_FLOOR).log10()
+}
+
+/// Builds the loudness of every [`WINDOW`] of samples as they are decoded, so
+/// the samples themselves never have to be kept around.
+#[derive(Debug, Default)]
+struct Envelope {
+	/// The samples of the window that isn't complete yet.
+	partial: Vec<f32>,
+	windows: Vec<f64>,
+}
+
+impl Envelope {
+	fn push(&mut self, mut samples: &[f32]) {
+		if !self.partial.is_empty() {
+			let (head, rest) = samples.split_at((WINDOW - self.partial.len()).min(samples.len()));
+			self.partial.extend_from_slice(head);
+			samples = rest;
+			if self.partial.len() < WINDOW {
+				return;
+			}
+			self.windows.push(loudness(&self.partial));
+			self.partial.clear();
+		}
+		let mut chunks = samples.chunks_exact(WINDOW);
+		self.windows.extend(chunks.by_ref().map(loudness));
+		self.partial.extend_from_slice(chunks.remainder());
+	}
+
+	/// The loudness of every complete window. A trailing partial window is
+	/// left out.
+	fn finish(self) -> Vec<f64> {
+		self.windows
+	}
+}
+
+/// The loudness envelope of an audio track, as returned by [`decode_audio`].
+struct DecodedAudio {
+	envelope: Vec<f64>,
+	/// The timestamp the audio stream starts at, in [`TIME_BASE`].
+	start: i64,
+}
+
+/// Decodes the best audio stream of a file into the loudness envelope of its
+/// mono downmix at [`SAMPLE_RATE`], starting from the beginning of the
+/// stream.
+fn decode_audio(path: &Path, cancel: &CancellationToken) -> Result<DecodedAudio> {
+	ffmpeg::init().wrap_err("failed to initialize ffmpeg")?;
+	let mut ictx = ffmpeg::format::input(&path)
+		.wrap_err_with(|| format!("failed to open input file at {}", path.display()))?;
+	let stream = ictx
+		.streams()
+		.best(ffmpeg::media::Type::Audio)
+		.wrap_err("input has no audio stream")?;
+	let stream_index = stream.index();
+	let start = match stream.start_time() {
+		start if start == ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+		start => start.rescale(stream.time_base(), TIME_BASE),
+	};
+	let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+		.wrap_err("failed to create audio decoder context")?
+		.decoder()
+		.audio()
+		.wrap_err("failed to open audio decoder")?;
+
+	let layout = match decoder.channel_layout() {
+		layout if layout.is_empty() => ffmpeg::ChannelLayout::default(decoder.channels() as i32),
+		layout => layout,
+	};
+	let mut resampler = ffmpeg::software::resampling::context::Context::get(
+		decoder.format(),
+		layout,
+		decoder.rate(),
+		ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed),
+		ffmpeg::ChannelLayout::MONO,
+		SAMPLE_RATE,
+	)
+	.wrap_err("failed to create audio resampler")?;
+
+	let mut envelope = Envelope::default();
+	let mut decoded = ffmpeg::frame::Audio::empty();
+	let mut resampled = ffmpeg::frame::Audio::empty();
+	let mut drain = |decoder: &mut ffmpeg::decoder::Audio,
+	                 resampler: &mut ffmpeg::software::resampling::Context,
+	                 envelope: &mut Envelope|
+	 -> Result<()> {
+		while decoder.receive_frame(&mut decoded).is_ok() {
+			if decoded.channel_layout().is_empty() {
+				decoded.set_channel_layout(layout);
+			}
+			resampler
+				.run(&decoded, &mut resampled)
+				.wrap_err("failed to resample audio")?;
+			envelope.push(&resampled.plane::<f32>(0)[..resampled.samples()]);
+		}
+		Ok(())
+	};
+
+	for (stream, packet) in ictx.packets() {
+		if cancel.is_cancelled() {
+			return Ok(DecodedAudio {
+				envelope: envelope.finish(),
+				start,
+			});
+		}
+		if stream.index() != stream_index {
+			continue;
+		}
+		decoder
+			.send_packet(&packet)
+			.wrap_err("failed to send packet to audio decoder")?;
+		drain(&mut decoder, &mut resampler, &mut envelope)?;
+	}
+	decoder
+		.send_eof()
+		.wrap_err("failed to flush audio decoder")?;
+	drain(&mut decoder, &mut resampler, &mut envelope)?;
+	// The resampler holds on to the last few samples until it's flushed.
+	loop {
+		resampler
+			.flush(&mut resampled)
+			.wrap_err("failed to flush audio resampler")?;
+		if resampled.samples() == 0 {
+			break;
+		}
+		envelope.push(&resampled.plane::<f32>(0)[..resampled.samples()]);
+	}
+	Ok(DecodedAudio {
+		envelope: envelope.finish(),
+		start,
+	})
+}
+
+/// The timestamp a window starts at, in [`TIME_BASE`].
+fn window_pts(window: usize) -> i64 {
+	(window * WINDOW) as i64
+}
+
+/// Finds every stretch of at least `min_duration` seconds quieter than
+/// `threshold_db`.
+fn find_silences(envelope: &[f64], threshold_db: f64, min_duration: f64) -> Vec<TimeRange> {
+	let mut silences = Vec::new();
+	let mut start = None;
+	// The extra loud window at the end closes off a trailing silence.
+	for (window, &db) in envelope.iter().chain([&f64::INFINITY]).enumerate() {
+		match (start, db < threshold_db) {
+			(None, true) => start = Some(window),
+			(Some(first), false) => {
+				let range = TimeRange::new(window_pts(first), window_pts(window));
+				if range.duration() as f64 / f64::from(SAMPLE_RATE) >= min_duration {
+					silences.push(range);
+				}
+				start = None;
+			}
+			_ => {}
+		}
+	}
+	silences
+}
+
+/// A short reference recording, such as an ad break jingle, to look for in
+/// the input's audio.
+#[derive(Debug, Clone)]
+pub struct AudioClip {
+	pub label: String,
+	/// The clip's envelope with its mean subtracted.
+	centered: Vec<f64>,
+	/// The euclidean norm of `centered`.
+	norm: f64,
+}
+
+impl AudioClip {
+	/// Loads the audio of a file as a reference clip.
+	pub fn load(path: &Path) -> Result<Self> {
+		let audio = decode_audio(path, &CancellationToken::new())
+			.wrap_err_with(|| format!("failed to decode audio clip {}", path.display()))?;
+		let label = path
+			.file_stem()
+			.map(|stem| stem.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		Self::from_envelope(label, &audio.envelope)
+			.wrap_err_with(|| format!("audio clip {} is too short", path.display()))
+	}
+
+	/// Creates a clip from a loudness envelope, or `None` if it's too short to
+	/// correlate.
+	fn from_envelope(label: String, envelope: &[f64]) -> Option<Self> {
+		if envelope.len() < 2 {
+			return None;
+		}
+		let mean = envelope.iter().sum::<f64>() / envelope.len() as f64;
+		let centered = envelope.iter().map(|db| db - mean).collect::<Vec<_>>();
+		let norm = centered.iter().map(|db| db * db).sum::<f64>().sqrt();
+		Some(Self {
+			label,
+			centered,
+			norm,
+		})
+	}
+
+	#[inline]
+	pub fn duration(&self) -> f64 {
+		window_pts(self.centered.len()) as f64 / f64::from(SAMPLE_RATE)
+	}
+
+	/// Slides the clip over the envelope, returning the time ranges where the
+	/// normalized cross-correlation reaches `threshold`. Overlapping hits are
+	/// collapsed into the best one.
+	fn find(&self, envelope: &[f64], threshold: f64) -> Vec<TimeRange> {
+		let len = self.centered.len();
+		if envelope.len() < len || self.norm <= f64::EPSILON {
+			return Vec::new();
+		}
+		// Prefix sums give the mean and variance of every window in O(1).
+		let mut sum = vec![0.0; envelope.len() + 1];
+		let mut sum_sq = vec![0.0; envelope.len() + 1];
+		for (i, &db) in envelope.iter().enumerate() {
+			sum[i + 1] = sum[i] + db;
+			sum_sq[i + 1] = sum_sq[i] + db * db;
+		}
+
+		let mut hits = Vec::new();
+		let mut best: Option<(usize, f64)> = None;
+		for offset in 0..=envelope.len() - len {
+			let window_sum = sum[offset + len] - sum[offset];
+			let window_sq = sum_sq[offset + len] - sum_sq[offset];
+			let variance = window_sq - window_sum * window_sum / len as f64;
+			let correlation = if variance <= f64::EPSILON {
+				0.0
+			} else {
+				// The clip is already centered, so the window's mean cancels
+				// out of the dot product.
+				let dot = self
+					.centered
+					.iter()
+					.zip(&envelope[offset..offset + len])
+					.map(|(a, b)| a * b)
+					.sum::<f64>();
+				dot / (self.norm * variance.sqrt())
+			};
+
+			match best {
+				Some((start, _)) if offset >= start + len => {
+					hits.push(start);
+					best = None;
+				}
+				_ => {}
+			}
+			if correlation >= threshold {
+				match best {
+					Some((_, score)) if score >= correlation => {}
+					_ => best = Some((offset, correlation)),
+				}
+			}
+		}
+		hits.extend(best.map(|(start, _)| start));
+		hits.into_iter()
+			.map(|start| TimeRange::new(window_pts(start), window_pts(start + len)))
+			.collect()
+	}
+}
+
+/// The time ranges flagged by an [`AudioAnalyzer`], in [`TIME_BASE`]. They
+/// use the same clock as the input's other streams, so an audio stream that
+/// starts after the video is accounted for.
+#[derive(Debug, Clone, Default)]
+pub struct AudioAnalysis {
+	pub silences: Vec<TimeRange>,
+	/// Every occurrence of a reference clip, with the clip's label.
+	pub clips: Vec<(String, TimeRange)>,
+}
+
+impl AudioAnalysis {
+	/// Every flagged time range, silences and clips alike, sorted by start.
+	pub fn ranges(&self) -> Vec<TimeRange> {
+		let mut ranges = self
+			.silences
+			.iter()
+			.copied()
+			.chain(self.clips.iter().map(|(_, range)| *range))
+			.collect::<Vec<_>>();
+		ranges.sort_unstable_by_key(|range| range.start);
+		ranges
+	}
+}
+
+/// Finds silences and reference clips in the audio of a video, to be removed
+/// alongside the frames flagged by the [`Detector`](crate::detect::Detector).
+#[derive(Debug, Clone)]
+pub struct AudioAnalyzer {
+	silence: Option<(f64, f64)>,
+	clips: Vec<AudioClip>,
+	clip_threshold: f64,
+}
+
+impl Default for AudioAnalyzer {
+	fn default() -> Self {
+		Self {
+			silence: None,
+			clips: Vec::new(),
+			clip_threshold: 0.8,
+		}
+	}
+}
+
+impl AudioAnalyzer {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Flag audio quieter than `threshold_db` (dBFS, e.g. -50) for at least
+	/// `min_duration` seconds.
+	pub fn silence(mut self, threshold_db: f64, min_duration: f64) -> Self {
+		self.silence = Some((threshold_db, min_duration));
+		self
+	}
+
+	/// The reference clips to look for.
+	pub fn clips(mut self, clips: Vec<AudioClip>) -> Self {
+		self.clips = clips;
+		self
+	}
+
+	/// The minimum normalized cross-correlation (0-1) between a reference
+	/// clip's loudness envelope and the audio for the clip to count as found.
+	pub fn clip_threshold(mut self, threshold: f64) -> Self {
+		self.clip_threshold = threshold;
+		self
+	}
+
+	/// Whether the analyzer would flag anything at all.
+	#[inline]
+	pub fn is_enabled(&self) -> bool {
+		self.silence.is_some() || !self.clips.is_empty()
+	}
+
+	/// Decodes and analyses the audio of the given input. Stops early with
+	/// partial results once the cancellation token is cancelled.
+	pub fn analyze(&self, input: &Path, cancel: &CancellationToken) -> Result<AudioAnalysis> {
+		let DecodedAudio { envelope, start } = decode_audio(input, cancel)
+			.wrap_err_with(|| format!("failed to decode audio of {}", input.display()))?;
+		let shift = |range: TimeRange| TimeRange::new(start + range.start, start + range.end);
+		let silences = match self.silence {
+			Some((threshold_db, min_duration)) => {
+				find_silences(&envelope, threshold_db, min_duration)
+					.into_iter()
+					.map(shift)
+					.collect()
+			}
+			None => Vec::new(),
+		};
+		let clips = self
+			.clips
+			.iter()
+			.flat_map(|clip| {
+				clip.find(&envelope, self.clip_threshold)
+					.into_iter()
+					.map(|range| (clip.label.clone(), shift(range)))
+			})
+			.collect();
+		Ok(AudioAnalysis { silences, clips })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn envelope_is_independent_of_chunking() {
+		let samples = (0..WINDOW * 7 + 13)
+			.map(|i| (i as f32 * 0.01).sin() * (i % 5) as f32 * 0.1)
+			.collect::<Vec<_>>();
+		let mut whole = Envelope::default();
+		whole.push(&samples);
+		let mut chunked = Envelope::default();
+		for chunk in samples.chunks(37) {
+			chunked.push(chunk);
+		}
+		let whole = whole.finish();
+		assert_eq!(whole.len(), 7);
+		assert_eq!(whole, chunked.finish());
+		assert_eq!(whole[0], loudness(&samples[..WINDOW]));
+	}
+
+	#[test]
+	fn silences() {
+		let envelope = [vec![-10.0; 10], vec![-80.0; 60], vec![-10.0; 10], vec![
+			-80.0;
+			20
+		]]
+		.concat();
+		assert_eq!(find_silences(&envelope, -50.0, 0.5), vec![TimeRange::new(
+			window_pts(10),
+			window_pts(70)
+		)]);
+		assert_eq!(find_silences(&envelope, -50.0, 0.1), vec![
+			TimeRange::new(window_pts(10), window_pts(70)),
+			TimeRange::new(window_pts(80), window_pts(100)),
+		]);
+		assert!(find_silences(&envelope, -90.0, 0.0).is_empty());
+		assert!(find_silences(&[], -50.0, 0.0).is_empty());
+	}
+
+	#[test]
+	fn clips() {
+		let pattern = [-40.0, -10.0, -30.0, -5.0, -20.0];
+		let clip = AudioClip::from_envelope("jingle".to_owned(), &pattern).unwrap();
+		let mut envelope = vec![-30.0; 80];
+		envelope[20..25].copy_from_slice(&pattern);
+		envelope[50..55].copy_from_slice(&pattern);
+		assert_eq!(clip.find(&envelope, 0.99), vec![
+			TimeRange::new(window_pts(20), window_pts(25)),
+			TimeRange::new(window_pts(50), window_pts(55)),
+		]);
+		assert!(clip.find(&[-30.0; 80], 0.5).is_empty());
+		assert!(clip.find(&pattern[..3], 0.5).is_empty());
+		assert!(AudioClip::from_envelope("short".to_owned(), &[-10.0]).is_none());
+	}
+}
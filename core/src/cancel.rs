@@ -0,0 +1,43 @@
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc,
+};
+
+/// A cheaply cloneable flag used to stop a running scan early.
+///
+/// Every clone shares the same state, so cancelling any of them cancels them
+/// all.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+	cancelled: Arc<AtomicBool>,
+	parent: Option<Box<CancellationToken>>,
+}
+
+impl CancellationToken {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Creates a token that is cancelled whenever this one is, but that can
+	/// also be cancelled on its own without affecting this one.
+	pub fn child_token(&self) -> Self {
+		Self {
+			cancelled: Arc::default(),
+			parent: Some(Box::new(self.clone())),
+		}
+	}
+
+	#[inline]
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	#[inline]
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+			|| self
+				.parent
+				.as_ref()
+				.is_some_and(|parent| parent.is_cancelled())
+	}
+}
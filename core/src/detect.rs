@@ -0,0 +1,82 @@
+pub mod blank;
+pub mod features;
+pub mod phash;
+
+use crate::frame::Frame;
+use color_eyre::eyre::Result;
+use opencv::core::{Point, Size};
+
+/// The outcome of running a [`Detector`] on a single frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Detection {
+	/// Whether the frame should be treated as matching.
+	pub matched: bool,
+	/// Whether the frame scored at least the detector's exit threshold and
+	/// wasn't vetoed, so it keeps an already started run going even if it
+	/// didn't match. Detectors without an exit threshold never set this.
+	pub sustains: bool,
+	/// Whether something vetoed a frame that would otherwise have matched or
+	/// sustained a run. Runs are never bridged across vetoed frames.
+	pub vetoed: bool,
+	/// The score of whatever marked the frame as matching, or the best score
+	/// of anything that could have if nothing did.
+	pub pos_score: f64,
+	/// The best score of anything that vetoes a match.
+	pub neg_score: f64,
+	/// The template scale factor that produced `pos_score`, for detectors
+	/// that match at multiple scales.
+	pub scale: Option<f64>,
+	/// The label of the template that produced `pos_score`.
+	pub pos_label: Option<String>,
+	/// The label of the template that produced `neg_score`.
+	pub neg_label: Option<String>,
+	/// The top-left corner of where the template that produced `pos_score`
+	/// was found in the frame, for detectors that locate templates.
+	pub location: Option<Point>,
+}
+
+/// Classifies frames as matching or not.
+///
+/// Every worker thread gets its own clone of the detector, so any scratch
+/// buffers it keeps around are never shared between threads.
+pub trait Detector: DetectorClone + Send + 'static {
+	/// Called once per video, before any frames are scored.
+	fn prepare(&mut self, _frame_size: Size) -> Result<()> {
+		Ok(())
+	}
+
+	fn score(&mut self, frame: &Frame) -> Result<Detection>;
+}
+
+/// Allows cloning boxed [`Detector`]s. Implemented for every detector that
+/// implements [`Clone`].
+pub trait DetectorClone {
+	fn clone_box(&self) -> Box<dyn Detector>;
+}
+
+impl<D> DetectorClone for D
+where
+	D: Detector + Clone,
+{
+	fn clone_box(&self) -> Box<dyn Detector> {
+		Box::new(self.clone())
+	}
+}
+
+impl Clone for Box<dyn Detector> {
+	fn clone(&self) -> Self {
+		(**self).clone_box()
+	}
+}
+
+impl Detector for Box<dyn Detector> {
+	#[inline]
+	fn prepare(&mut self, frame_size: Size) -> Result<()> {
+		(**self).prepare(frame_size)
+	}
+
+	#[inline]
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		(**self).score(frame)
+	}
+}
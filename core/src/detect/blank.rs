@@ -0,0 +1,105 @@
+use crate::{
+	detect::{Detection, Detector},
+	frame::{Frame, FrameStats},
+};
+use color_eyre::eyre::Result;
+
+/// Flags black frames, where the average (and optionally the brightest) luma
+/// is at or below a threshold.
+///
+/// `pos_score` is how dark the frame is, from 0 to 1.
+#[derive(Debug, Clone, Copy)]
+pub struct BlackFrameDetector {
+	mean_threshold: f64,
+	max_threshold: Option<f64>,
+}
+
+impl Default for BlackFrameDetector {
+	fn default() -> Self {
+		Self {
+			mean_threshold: 16.0,
+			max_threshold: None,
+		}
+	}
+}
+
+impl BlackFrameDetector {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The highest average luma (0-255) a frame may have to count as black.
+	pub fn mean_threshold(mut self, threshold: f64) -> Self {
+		self.mean_threshold = threshold;
+		self
+	}
+
+	/// The highest luma (0-255) any part of a frame may have to count as
+	/// black, which rules out dark frames with some bright content in them.
+	pub fn max_threshold(mut self, threshold: Option<f64>) -> Self {
+		self.max_threshold = threshold;
+		self
+	}
+}
+
+impl Detector for BlackFrameDetector {
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		let FrameStats {
+			mean_luma,
+			max_luma,
+			..
+		} = *frame.stats();
+		let bright = match self.max_threshold {
+			Some(threshold) => max_luma > threshold,
+			None => false,
+		};
+		Ok(Detection {
+			matched: mean_luma <= self.mean_threshold && !bright,
+			pos_score: 1.0 - mean_luma / 255.0,
+			..Detection::default()
+		})
+	}
+}
+
+/// Flags frozen frames, which barely differ from the frame before them, as
+/// happens when a stream drops out.
+///
+/// `pos_score` is how similar the frame is to the previous one, from 0 to 1.
+#[derive(Debug, Clone, Copy)]
+pub struct FrozenFrameDetector {
+	threshold: f64,
+}
+
+impl Default for FrozenFrameDetector {
+	fn default() -> Self {
+		Self { threshold: 0.5 }
+	}
+}
+
+impl FrozenFrameDetector {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The highest mean luma difference (0-255) to the previous frame for a
+	/// frame to count as frozen. Compression noise alone usually stays well
+	/// below 1.
+	pub fn threshold(mut self, threshold: f64) -> Self {
+		self.threshold = threshold;
+		self
+	}
+}
+
+impl Detector for FrozenFrameDetector {
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		// The first frame has nothing to be frozen on.
+		let Some(difference) = frame.stats().difference else {
+			return Ok(Detection::default());
+		};
+		Ok(Detection {
+			matched: difference <= self.threshold,
+			pos_score: 1.0 - difference / 255.0,
+			..Detection::default()
+		})
+	}
+}
@@ -0,0 +1,401 @@
+use crate::{
+	detect::{Detection, Detector},
+	frame::Frame,
+	templates::Template,
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use opencv::{
+	calib3d,
+	core::{
+		self, DMatch, KeyPoint, KeyPointTraitConst, Mat, MatTraitConst, Point2f, Ptr, Rect, Size,
+		Vector, NORM_HAMMING,
+	},
+	features2d::{
+		AKAZE_DescriptorType, BFMatcher, DescriptorMatcherTraitConst, Feature2DTrait,
+		KAZE_DiffusivityType, ORB_ScoreType, AKAZE, ORB,
+	},
+};
+use std::str::FromStr;
+
+/// Homographies need at least four point correspondences.
+const MIN_CORRESPONDENCES: usize = 4;
+
+/// The keypoint detector and descriptor extractor to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeypointAlgorithm {
+	/// Fast, but only somewhat tolerant of scaling.
+	#[default]
+	Orb,
+	/// Slower, but more robust to scaling and blur.
+	Akaze,
+}
+
+impl FromStr for KeypointAlgorithm {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"orb" => Ok(Self::Orb),
+			"akaze" => Ok(Self::Akaze),
+			_ => Err(eyre!(
+				"unknown keypoint algorithm '{s}', expected orb or akaze"
+			)),
+		}
+	}
+}
+
+enum Extractor {
+	Orb(Ptr<ORB>),
+	Akaze(Ptr<AKAZE>),
+}
+
+impl Extractor {
+	fn new(algorithm: KeypointAlgorithm, max_features: i32) -> Result<Self> {
+		Ok(match algorithm {
+			KeypointAlgorithm::Orb => Self::Orb(
+				ORB::create(
+					max_features,
+					1.2,
+					8,
+					31,
+					0,
+					2,
+					ORB_ScoreType::HARRIS_SCORE,
+					31,
+					20,
+				)
+				.wrap_err("failed to create orb extractor")?,
+			),
+			KeypointAlgorithm::Akaze => Self::Akaze(
+				AKAZE::create(
+					AKAZE_DescriptorType::DESCRIPTOR_MLDB,
+					0,
+					3,
+					0.001,
+					4,
+					4,
+					KAZE_DiffusivityType::DIFF_PM_G2,
+				)
+				.wrap_err("failed to create akaze extractor")?,
+			),
+		})
+	}
+
+	fn detect(&mut self, image: &Mat, mask: Option<&Mat>) -> Result<Features> {
+		let mut keypoints = Vector::<KeyPoint>::new();
+		let mut descriptors = Mat::default();
+		let no_mask = Mat::default();
+		let mask = mask.unwrap_or(&no_mask);
+		match self {
+			Self::Orb(orb) => {
+				orb.detect_and_compute(image, mask, &mut keypoints, &mut descriptors, false)
+			}
+			Self::Akaze(akaze) => {
+				akaze.detect_and_compute(image, mask, &mut keypoints, &mut descriptors, false)
+			}
+		}
+		.wrap_err("failed to compute keypoints")?;
+		Ok(Features {
+			points: keypoints.iter().map(|keypoint| keypoint.pt()).collect(),
+			descriptors,
+		})
+	}
+}
+
+/// The keypoints of an image and their descriptors, in the same order.
+#[derive(Clone)]
+struct Features {
+	points: Vec<Point2f>,
+	descriptors: Mat,
+}
+
+#[derive(Clone)]
+struct Reference {
+	label: String,
+	features: Features,
+}
+
+/// Scores frames by how many keypoints of a template can be mapped onto the
+/// frame with a single homography, which tolerates overlays that are rotated,
+/// scaled, or slightly perspective-warped.
+///
+/// The scores in the returned [`Detection`]s are inlier counts, not 0-1
+/// correlations.
+pub struct FeatureDetector {
+	pos_templates: Vec<Template>,
+	neg_templates: Vec<Template>,
+	pos_refs: Vec<Reference>,
+	neg_refs: Vec<Reference>,
+	algorithm: KeypointAlgorithm,
+	max_features: i32,
+	min_inliers: usize,
+	exit_inliers: Option<usize>,
+	ratio: f32,
+	reprojection_threshold: f64,
+	bounds: Option<Rect>,
+	/// Created lazily, as OpenCV's extractors and matchers can't be cloned.
+	extractor: Option<Extractor>,
+	matcher: Option<Ptr<BFMatcher>>,
+}
+
+impl Clone for FeatureDetector {
+	fn clone(&self) -> Self {
+		Self {
+			pos_templates: self.pos_templates.clone(),
+			neg_templates: self.neg_templates.clone(),
+			pos_refs: self.pos_refs.clone(),
+			neg_refs: self.neg_refs.clone(),
+			algorithm: self.algorithm,
+			max_features: self.max_features,
+			min_inliers: self.min_inliers,
+			exit_inliers: self.exit_inliers,
+			ratio: self.ratio,
+			reprojection_threshold: self.reprojection_threshold,
+			bounds: self.bounds,
+			extractor: None,
+			matcher: None,
+		}
+	}
+}
+
+impl FeatureDetector {
+	pub fn new(pos_templates: Vec<Template>, neg_templates: Vec<Template>) -> Self {
+		Self {
+			pos_templates,
+			neg_templates,
+			pos_refs: Vec::new(),
+			neg_refs: Vec::new(),
+			algorithm: KeypointAlgorithm::default(),
+			max_features: 1000,
+			min_inliers: 12,
+			exit_inliers: None,
+			ratio: 0.75,
+			reprojection_threshold: 5.0,
+			bounds: None,
+			extractor: None,
+			matcher: None,
+		}
+	}
+
+	/// The keypoint detector and descriptor extractor to use.
+	pub fn algorithm(mut self, algorithm: KeypointAlgorithm) -> Self {
+		self.algorithm = algorithm;
+		self
+	}
+
+	/// The maximum amount of keypoints ORB keeps per image.
+	pub fn max_features(mut self, max_features: i32) -> Self {
+		self.max_features = max_features;
+		self
+	}
+
+	/// How many keypoints must agree on the homography for a template to
+	/// count as found.
+	pub fn min_inliers(mut self, min_inliers: usize) -> Self {
+		self.min_inliers = min_inliers.max(MIN_CORRESPONDENCES);
+		self
+	}
+
+	/// How many inliers frames that didn't match still need to keep an
+	/// already started run going.
+	pub fn exit_inliers(mut self, exit_inliers: Option<usize>) -> Self {
+		self.exit_inliers = exit_inliers;
+		self
+	}
+
+	/// Lowe's ratio test threshold: a keypoint match is only kept if it's this
+	/// much closer than the second best candidate.
+	pub fn ratio(mut self, ratio: f32) -> Self {
+		self.ratio = ratio;
+		self
+	}
+
+	/// The maximum distance in pixels a keypoint may be from where the
+	/// homography maps it and still count as an inlier.
+	pub fn reprojection_threshold(mut self, threshold: f64) -> Self {
+		self.reprojection_threshold = threshold;
+		self
+	}
+
+	/// The region of interest to look for templates in.
+	pub fn bounds(mut self, bounds: Option<Rect>) -> Self {
+		self.bounds = bounds;
+		self
+	}
+}
+
+/// Lazily creates an OpenCV object that can't be cloned between threads.
+fn get_or_create<T>(slot: &mut Option<T>, create: impl FnOnce() -> Result<T>) -> Result<&mut T> {
+	if slot.is_none() {
+		*slot = Some(create()?);
+	}
+	Ok(slot.as_mut().expect("slot was just filled"))
+}
+
+fn references(extractor: &mut Extractor, templates: &[Template]) -> Result<Vec<Reference>> {
+	let mut references = Vec::with_capacity(templates.len());
+	for template in templates {
+		let features = extractor
+			.detect(&template.image, template.mask.as_ref())
+			.wrap_err_with(|| format!("failed to find keypoints of {}", template.label))?;
+		if features.points.len() < MIN_CORRESPONDENCES {
+			return Err(eyre!(
+				"template {} only has {} keypoints, it needs more detail to be matched",
+				template.label,
+				features.points.len()
+			));
+		}
+		references.push(Reference {
+			label: template.label.clone(),
+			features,
+		});
+	}
+	Ok(references)
+}
+
+/// Counts how many of the reference's keypoints fit a single homography onto
+/// the frame.
+fn inliers(
+	matcher: &Ptr<BFMatcher>,
+	ratio: f32,
+	reprojection_threshold: f64,
+	reference: &Reference,
+	frame: &Features,
+) -> Result<usize> {
+	if frame.points.len() < MIN_CORRESPONDENCES {
+		return Ok(0);
+	}
+	let mut matches = Vector::<Vector<DMatch>>::new();
+	matcher
+		.knn_train_match(
+			&reference.features.descriptors,
+			&frame.descriptors,
+			&mut matches,
+			2,
+			&core::no_array(),
+			false,
+		)
+		.wrap_err("failed to match descriptors")?;
+
+	let mut src = Vector::<Point2f>::new();
+	let mut dst = Vector::<Point2f>::new();
+	for pair in matches.iter() {
+		let (Ok(best), Ok(second)) = (pair.get(0), pair.get(1)) else {
+			continue;
+		};
+		if best.distance < ratio * second.distance {
+			src.push(reference.features.points[best.query_idx as usize]);
+			dst.push(frame.points[best.train_idx as usize]);
+		}
+	}
+	if src.len() < MIN_CORRESPONDENCES {
+		return Ok(0);
+	}
+
+	let mut mask = Mat::default();
+	let homography = calib3d::find_homography(
+		&src,
+		&dst,
+		&mut mask,
+		calib3d::RANSAC,
+		reprojection_threshold,
+	)
+	.wrap_err("failed to find homography")?;
+	if homography.empty() {
+		return Ok(0);
+	}
+	Ok(core::count_non_zero(&mask).wrap_err("failed to count inliers")? as usize)
+}
+
+impl FeatureDetector {
+	/// The highest inlier count over the references and the label of the
+	/// reference it belongs to, stopping early once one reaches
+	/// `min_inliers`.
+	fn best_inliers(
+		&mut self,
+		negative: bool,
+		frame: &Features,
+	) -> Result<(usize, Option<String>)> {
+		let matcher = get_or_create(&mut self.matcher, || {
+			// Both ORB and AKAZE's default MLDB descriptors are binary.
+			BFMatcher::create(NORM_HAMMING, false).wrap_err("failed to create matcher")
+		})?;
+		let references = if negative {
+			&self.neg_refs
+		} else {
+			&self.pos_refs
+		};
+		let mut best = 0;
+		let mut label = None;
+		for reference in references {
+			let count = inliers(
+				matcher,
+				self.ratio,
+				self.reprojection_threshold,
+				reference,
+				frame,
+			)
+			.wrap_err_with(|| format!("failed to match template {}", reference.label))?;
+			if count > best || label.is_none() {
+				best = count;
+				label = Some(reference.label.clone());
+			}
+			if best >= self.min_inliers {
+				break;
+			}
+		}
+		Ok((best, label))
+	}
+}
+
+impl Detector for FeatureDetector {
+	fn prepare(&mut self, _frame_size: Size) -> Result<()> {
+		let (algorithm, max_features) = (self.algorithm, self.max_features);
+		let extractor = get_or_create(&mut self.extractor, || {
+			Extractor::new(algorithm, max_features)
+		})?;
+		self.pos_refs = references(extractor, &self.pos_templates)
+			.wrap_err("failed to prepare positive templates")?;
+		self.neg_refs = references(extractor, &self.neg_templates)
+			.wrap_err("failed to prepare negative templates")?;
+		Ok(())
+	}
+
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		let roi;
+		let image = match &self.bounds {
+			Some(bounds) => {
+				roi = Mat::roi(frame.frame(), *bounds)
+					.wrap_err_with(|| format!("invalid roi: {bounds:?}"))?;
+				&roi
+			}
+			None => frame.frame(),
+		};
+		let (algorithm, max_features) = (self.algorithm, self.max_features);
+		let features = get_or_create(&mut self.extractor, || {
+			Extractor::new(algorithm, max_features)
+		})?
+		.detect(image, None)
+		.wrap_err("failed to find keypoints of frame")?;
+
+		let (pos, pos_label) = self.best_inliers(false, &features)?;
+		let mut detection = Detection {
+			matched: pos >= self.min_inliers,
+			sustains: self.exit_inliers.is_some_and(|exit| pos >= exit),
+			pos_score: pos as f64,
+			pos_label,
+			..Detection::default()
+		};
+		if detection.matched || detection.sustains {
+			let (neg, neg_label) = self.best_inliers(true, &features)?;
+			detection.neg_score = neg as f64;
+			detection.neg_label = neg_label;
+			if neg >= self.min_inliers {
+				detection.matched = false;
+				detection.sustains = false;
+				detection.vetoed = true;
+			}
+		}
+		Ok(detection)
+	}
+}
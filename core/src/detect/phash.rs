@@ -0,0 +1,255 @@
+use crate::{
+	detect::{Detection, Detector},
+	fixup::ColorMode,
+	frame::Frame,
+	templates::Template,
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use opencv::{
+	core::{self, Mat, MatTraitConst, Size, CV_32F},
+	imgproc,
+};
+use std::str::FromStr;
+
+/// The amount of bits in a hash.
+const HASH_BITS: u32 = 64;
+
+/// How whole frames are reduced to a 64-bit hash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+	/// The low frequencies of the discrete cosine transform, compared to
+	/// their median. Robust to compression and small brightness changes.
+	#[default]
+	PHash,
+	/// Brightness differences between horizontally adjacent pixels. Cheaper,
+	/// but less tolerant of contrast changes.
+	DHash,
+}
+
+impl FromStr for HashAlgorithm {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"phash" => Ok(Self::PHash),
+			"dhash" => Ok(Self::DHash),
+			_ => Err(eyre!(
+				"unknown hash algorithm '{s}', expected phash or dhash"
+			)),
+		}
+	}
+}
+
+impl HashAlgorithm {
+	/// Hashes the brightness of an 8-bit image in the given colour mode: the
+	/// luma of BGR images, or the value channel of HSV images.
+	pub fn hash(&self, image: &Mat, color_mode: ColorMode) -> Result<u64> {
+		let mut grey = Mat::default();
+		let image = match color_mode {
+			_ if image.channels() == 1 => image,
+			ColorMode::Grey | ColorMode::Bgr => {
+				imgproc::cvt_color(image, &mut grey, imgproc::COLOR_BGR2GRAY, 0)
+					.wrap_err("failed to convert image to greyscale")?;
+				&grey
+			}
+			ColorMode::Hsv => {
+				core::extract_channel(image, &mut grey, 2)
+					.wrap_err("failed to extract value channel")?;
+				&grey
+			}
+		};
+		match self {
+			Self::PHash => phash(image),
+			Self::DHash => dhash(image),
+		}
+	}
+}
+
+fn shrink(image: &Mat, width: i32, height: i32) -> Result<Mat> {
+	let mut small = Mat::default();
+	imgproc::resize(
+		image,
+		&mut small,
+		Size::new(width, height),
+		0.0,
+		0.0,
+		imgproc::INTER_AREA,
+	)
+	.wrap_err("failed to shrink image")?;
+	Ok(small)
+}
+
+fn phash(image: &Mat) -> Result<u64> {
+	let small = shrink(image, 32, 32)?;
+	let mut float = Mat::default();
+	small
+		.convert_to(&mut float, CV_32F, 1.0, 0.0)
+		.wrap_err("failed to convert image to floating point")?;
+	let mut dct = Mat::default();
+	core::dct(&float, &mut dct, 0).wrap_err("failed to compute dct")?;
+
+	let mut coefficients = [0f32; HASH_BITS as usize];
+	for (i, coefficient) in coefficients.iter_mut().enumerate() {
+		*coefficient = *dct
+			.at_2d::<f32>((i / 8) as i32, (i % 8) as i32)
+			.wrap_err("failed to read dct coefficient")?;
+	}
+	// The DC term is just the average brightness, so it's left out of the
+	// median.
+	let mut sorted = coefficients[1..].to_vec();
+	sorted.sort_unstable_by(f32::total_cmp);
+	let median = sorted[sorted.len() / 2];
+	Ok(coefficients
+		.iter()
+		.enumerate()
+		.filter(|(_, coefficient)| **coefficient > median)
+		.fold(0, |hash, (i, _)| hash | (1 << i)))
+}
+
+fn dhash(image: &Mat) -> Result<u64> {
+	let small = shrink(image, 9, 8)?;
+	let mut hash = 0;
+	for y in 0..8 {
+		for x in 0..8 {
+			let left = *small.at_2d::<u8>(y, x).wrap_err("failed to read pixel")?;
+			let right = *small
+				.at_2d::<u8>(y, x + 1)
+				.wrap_err("failed to read pixel")?;
+			if left > right {
+				hash |= 1 << (y * 8 + x);
+			}
+		}
+	}
+	Ok(hash)
+}
+
+#[derive(Clone)]
+struct Reference {
+	label: String,
+	hash: u64,
+}
+
+/// Flags whole frames that look like one of the reference images, such as
+/// intro slates or "we'll be right back" cards, by comparing perceptual hashes.
+///
+/// This is far cheaper than template matching a full frame. Scores in the
+/// returned [`Detection`]s are the fraction of hash bits that agree, from 0 to
+/// 1.
+#[derive(Clone)]
+pub struct HashDetector {
+	pos_templates: Vec<Template>,
+	neg_templates: Vec<Template>,
+	pos_refs: Vec<Reference>,
+	neg_refs: Vec<Reference>,
+	algorithm: HashAlgorithm,
+	max_distance: u32,
+	exit_distance: Option<u32>,
+	color_mode: ColorMode,
+}
+
+impl HashDetector {
+	pub fn new(pos_templates: Vec<Template>, neg_templates: Vec<Template>) -> Self {
+		Self {
+			pos_templates,
+			neg_templates,
+			pos_refs: Vec::new(),
+			neg_refs: Vec::new(),
+			algorithm: HashAlgorithm::default(),
+			max_distance: 10,
+			exit_distance: None,
+			color_mode: ColorMode::default(),
+		}
+	}
+
+	pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+		self.algorithm = algorithm;
+		self
+	}
+
+	/// The maximum Hamming distance (0-64) between a frame's hash and a
+	/// reference's hash for the frame to count as that reference.
+	pub fn max_distance(mut self, max_distance: u32) -> Self {
+		self.max_distance = max_distance.min(HASH_BITS);
+		self
+	}
+
+	/// The maximum Hamming distance at which frames that didn't match still
+	/// keep an already started run going.
+	pub fn exit_distance(mut self, exit_distance: Option<u32>) -> Self {
+		self.exit_distance = exit_distance.map(|distance| distance.min(HASH_BITS));
+		self
+	}
+
+	/// The colour mode frames and templates were preprocessed in. This must
+	/// match the preprocessor's.
+	pub fn color_mode(mut self, color_mode: ColorMode) -> Self {
+		self.color_mode = color_mode;
+		self
+	}
+
+	fn references(&self, templates: &[Template]) -> Result<Vec<Reference>> {
+		templates
+			.iter()
+			.map(|template| {
+				Ok(Reference {
+					label: template.label.clone(),
+					hash: self
+						.algorithm
+						.hash(&template.image, self.color_mode)
+						.wrap_err_with(|| format!("failed to hash {}", template.label))?,
+				})
+			})
+			.collect()
+	}
+
+	fn similarity(distance: u32) -> f64 {
+		1.0 - f64::from(distance) / f64::from(HASH_BITS)
+	}
+}
+
+/// The reference closest to the hash, and the Hamming distance to it.
+fn closest(hash: u64, references: &[Reference]) -> Option<(u32, &Reference)> {
+	references
+		.iter()
+		.map(|reference| ((hash ^ reference.hash).count_ones(), reference))
+		.min_by_key(|(distance, _)| *distance)
+}
+
+impl Detector for HashDetector {
+	fn prepare(&mut self, _frame_size: Size) -> Result<()> {
+		self.pos_refs = self
+			.references(&self.pos_templates)
+			.wrap_err("failed to hash positive templates")?;
+		self.neg_refs = self
+			.references(&self.neg_templates)
+			.wrap_err("failed to hash negative templates")?;
+		Ok(())
+	}
+
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		let hash = self
+			.algorithm
+			.hash(frame.frame(), self.color_mode)
+			.wrap_err("failed to hash frame")?;
+		let Some((pos, pos_ref)) = closest(hash, &self.pos_refs) else {
+			return Ok(Detection::default());
+		};
+		let mut detection = Detection {
+			matched: pos <= self.max_distance,
+			sustains: self.exit_distance.is_some_and(|exit| pos <= exit),
+			pos_score: Self::similarity(pos),
+			pos_label: Some(pos_ref.label.clone()),
+			..Detection::default()
+		};
+		if let Some((neg, neg_ref)) = closest(hash, &self.neg_refs) {
+			detection.neg_score = Self::similarity(neg);
+			detection.neg_label = Some(neg_ref.label.clone());
+			if neg <= self.max_distance && (detection.matched || detection.sustains) {
+				detection.matched = false;
+				detection.sustains = false;
+				detection.vetoed = true;
+			}
+		}
+		Ok(detection)
+	}
+}
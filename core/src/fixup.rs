@@ -1,34 +1,266 @@
-use color_eyre::eyre::{Result, WrapErr};
-use opencv::core::{Mat, Size, BORDER_DEFAULT, NORM_MINMAX};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use opencv::{
+	core::{self, Mat, MatTraitConst, Size, Vector, BORDER_DEFAULT, CV_16S, NORM_MINMAX},
+	imgproc::{self, CLAHETrait},
+};
+use std::str::FromStr;
 
-const BLUR_K_SIZE: Size = Size::new(5, 5);
+const BLUR_K_SIZE: i32 = 5;
 
-pub fn fixup_frame(base_image: &Mat, mid_a: &mut Mat, mid_b: &mut Mat, grey: bool) -> Result<Mat> {
-	let image = if grey {
-		opencv::imgproc::cvt_color(base_image, mid_a, opencv::imgproc::COLOR_BGR2GRAY, 0)
-			.wrap_err("failed to convert frame to greyscale")?;
-		mid_a
+/// The colour space frames and templates are matched in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+	/// Single channel greyscale, ignoring colour entirely.
+	#[default]
+	Grey,
+	/// Blue, green and red channels, matched together.
+	Bgr,
+	/// Hue, saturation and value channels, matched together.
+	Hsv,
+}
+
+impl ColorMode {
+	/// The amount of channels a frame has after [`fixup_frame`].
+	pub fn channels(&self) -> usize {
+		match self {
+			Self::Grey => 1,
+			Self::Bgr | Self::Hsv => 3,
+		}
+	}
+}
+
+impl FromStr for ColorMode {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"grey" | "gray" => Ok(Self::Grey),
+			"bgr" | "rgb" | "color" | "colour" => Ok(Self::Bgr),
+			"hsv" => Ok(Self::Hsv),
+			_ => Err(eyre!(
+				"unknown color mode '{s}', expected grey, bgr, or hsv"
+			)),
+		}
+	}
+}
+
+/// A single preprocessing step, applied identically to templates and frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+	/// Gaussian blur with the given (odd) kernel size.
+	Blur(i32),
+	/// Contrast limited adaptive histogram equalization.
+	Clahe { clip_limit: f64, tile_size: i32 },
+	/// Canny edge detection with the given hysteresis thresholds.
+	Canny { low: f64, high: f64 },
+	/// Sobel gradient magnitude with the given kernel size (1, 3, 5, or 7).
+	Sobel(i32),
+	/// Min-max normalization to the full 0-255 range.
+	Normalize,
+}
+
+impl Step {
+	pub fn apply(&self, src: &Mat, dst: &mut Mat) -> Result<()> {
+		match *self {
+			Self::Blur(k_size) => imgproc::gaussian_blur(
+				src,
+				dst,
+				Size::new(k_size, k_size),
+				0.0,
+				0.0,
+				BORDER_DEFAULT,
+			)
+			.wrap_err("failed to apply gaussian blur"),
+			Self::Clahe {
+				clip_limit,
+				tile_size,
+			} => {
+				let mut clahe = imgproc::create_clahe(clip_limit, Size::new(tile_size, tile_size))
+					.wrap_err("failed to create clahe")?;
+				if src.channels() == 1 {
+					return clahe.apply(src, dst).wrap_err("failed to apply clahe");
+				}
+				// CLAHE only works on a single channel, so equalize each one.
+				let mut channels = Vector::<Mat>::new();
+				core::split(src, &mut channels).wrap_err("failed to split channels")?;
+				let mut equalized = Vector::<Mat>::new();
+				for channel in channels.iter() {
+					let mut out = Mat::default();
+					clahe
+						.apply(&channel, &mut out)
+						.wrap_err("failed to apply clahe")?;
+					equalized.push(out);
+				}
+				core::merge(&equalized, dst).wrap_err("failed to merge channels")
+			}
+			Self::Canny { low, high } => {
+				imgproc::canny(src, dst, low, high, 3, false).wrap_err("failed to apply canny")
+			}
+			Self::Sobel(k_size) => {
+				// Gradients are computed at 16-bit so negative slopes aren't
+				// clipped, then folded back to 8-bit magnitudes.
+				let mut grad = Mat::default();
+				let mut grad_x = Mat::default();
+				let mut grad_y = Mat::default();
+				imgproc::sobel(
+					src,
+					&mut grad,
+					CV_16S,
+					1,
+					0,
+					k_size,
+					1.0,
+					0.0,
+					BORDER_DEFAULT,
+				)
+				.wrap_err("failed to apply horizontal sobel")?;
+				core::convert_scale_abs(&grad, &mut grad_x, 1.0, 0.0)
+					.wrap_err("failed to convert horizontal gradient")?;
+				imgproc::sobel(
+					src,
+					&mut grad,
+					CV_16S,
+					0,
+					1,
+					k_size,
+					1.0,
+					0.0,
+					BORDER_DEFAULT,
+				)
+				.wrap_err("failed to apply vertical sobel")?;
+				core::convert_scale_abs(&grad, &mut grad_y, 1.0, 0.0)
+					.wrap_err("failed to convert vertical gradient")?;
+				core::add_weighted(&grad_x, 0.5, &grad_y, 0.5, 0.0, dst, -1)
+					.wrap_err("failed to combine gradients")
+			}
+			Self::Normalize => {
+				core::normalize(src, dst, 0.0, 255.0, NORM_MINMAX, -1, &Mat::default())
+					.wrap_err("failed to normalize image")
+			}
+		}
+	}
+}
+
+impl FromStr for Step {
+	type Err = color_eyre::eyre::Report;
+
+	/// Parses a step such as `blur:5`, `clahe:2.0:8`, `canny:50:150`,
+	/// `sobel:3`, or `normalize`.
+	fn from_str(s: &str) -> Result<Self> {
+		let mut parts = s.trim().split(':');
+		let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+		let args = parts
+			.map(|arg| {
+				f64::from_str(arg.trim()).wrap_err_with(|| format!("invalid number '{arg}'"))
+			})
+			.collect::<Result<Vec<f64>>>()?;
+		let step = match (name.as_str(), args.as_slice()) {
+			("blur", []) => Self::Blur(BLUR_K_SIZE),
+			("blur", [k_size]) => Self::Blur(*k_size as i32),
+			("clahe", []) => Self::Clahe {
+				clip_limit: 2.0,
+				tile_size: 8,
+			},
+			("clahe", [clip_limit]) => Self::Clahe {
+				clip_limit: *clip_limit,
+				tile_size: 8,
+			},
+			("clahe", [clip_limit, tile_size]) => Self::Clahe {
+				clip_limit: *clip_limit,
+				tile_size: *tile_size as i32,
+			},
+			("canny", []) => Self::Canny {
+				low: 50.0,
+				high: 150.0,
+			},
+			("canny", [low, high]) => Self::Canny {
+				low: *low,
+				high: *high,
+			},
+			("sobel", []) => Self::Sobel(3),
+			("sobel", [k_size]) => Self::Sobel(*k_size as i32),
+			("normalize", []) => Self::Normalize,
+			_ => return Err(eyre!("invalid preprocessing step '{s}'")),
+		};
+		match step {
+			Self::Blur(k_size) if k_size <= 0 || k_size % 2 == 0 => Err(eyre!(
+				"blur kernel size must be odd and positive, got {k_size}"
+			)),
+			Self::Sobel(k_size) if !matches!(k_size, 1 | 3 | 5 | 7) => Err(eyre!(
+				"sobel kernel size must be 1, 3, 5, or 7, got {k_size}"
+			)),
+			Self::Clahe { tile_size, .. } if tile_size <= 0 => {
+				Err(eyre!("clahe tile size must be positive, got {tile_size}"))
+			}
+			step => Ok(step),
+		}
+	}
+}
+
+/// Parses a comma-separated list of [`Step`]s, where `none` means no steps at
+/// all.
+pub fn parse_steps(s: &str) -> Result<Vec<Step>> {
+	if s.trim().eq_ignore_ascii_case("none") || s.trim().is_empty() {
+		return Ok(Vec::new());
+	}
+	s.split(',').map(Step::from_str).collect()
+}
+
+/// How templates and frames are prepared before matching: a colour space
+/// conversion followed by a chain of [`Step`]s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preprocessor {
+	pub color_mode: ColorMode,
+	pub steps: Vec<Step>,
+}
+
+impl Default for Preprocessor {
+	fn default() -> Self {
+		Self {
+			color_mode: ColorMode::default(),
+			steps: vec![Step::Blur(BLUR_K_SIZE), Step::Normalize],
+		}
+	}
+}
+
+/// Converts a BGR image into the preprocessor's colour mode, then runs every
+/// step on it, using the two intermediate buffers to avoid reallocating.
+pub fn fixup_frame(
+	base_image: &Mat,
+	mid_a: &mut Mat,
+	mid_b: &mut Mat,
+	preprocessor: &Preprocessor,
+) -> Result<Mat> {
+	// The result of the previous step always lives in mid_a, unless nothing
+	// has been done to the base image yet.
+	let mut converted = true;
+	match preprocessor.color_mode {
+		ColorMode::Grey => imgproc::cvt_color(base_image, mid_a, imgproc::COLOR_BGR2GRAY, 0)
+			.wrap_err("failed to convert frame to greyscale")?,
+		ColorMode::Hsv => imgproc::cvt_color(base_image, mid_a, imgproc::COLOR_BGR2HSV, 0)
+			.wrap_err("failed to convert frame to hsv")?,
+		ColorMode::Bgr => converted = false,
+	}
+	for step in &preprocessor.steps {
+		if converted {
+			step.apply(mid_a, mid_b)?;
+		} else {
+			step.apply(base_image, mid_b)?;
+		}
+		std::mem::swap(mid_a, mid_b);
+		converted = true;
+	}
+	if converted {
+		Ok(std::mem::take(mid_a))
 	} else {
 		base_image
-	};
-	opencv::imgproc::gaussian_blur(&image, mid_b, BLUR_K_SIZE, 0.0, 0.0, BORDER_DEFAULT)
-		.wrap_err("failed to apply gaussian blur")?;
-	let mut result = Mat::default();
-	opencv::core::normalize(
-		mid_b,
-		&mut result,
-		0.0,
-		255.0,
-		NORM_MINMAX,
-		-1,
-		&Mat::default(),
-	)
-	.wrap_err("failed to normalize image")?;
-	Ok(result)
-}
-
-pub fn fixup_frame_2(base_image: &Mat, grey: bool) -> Result<Mat> {
+			.try_clone()
+			.wrap_err("failed to copy unprocessed frame")
+	}
+}
+
+pub fn fixup_frame_2(base_image: &Mat, preprocessor: &Preprocessor) -> Result<Mat> {
 	let mut mid_a = Mat::default();
 	let mut mid_b = Mat::default();
-	fixup_frame(base_image, &mut mid_a, &mut mid_b, grey)
+	fixup_frame(base_image, &mut mid_a, &mut mid_b, preprocessor)
 }
@@ -2,16 +2,120 @@ pub mod cpu;
 //#[cfg(feature = "cuda")]
 // pub mod gpu;
 
+use crate::{
+	cancel::CancellationToken,
+	detect::Detection,
+	fixup::Preprocessor,
+	progress::{ProgressEvent, ProgressObserver},
+};
 use color_eyre::eyre::{eyre, Context, Result};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use opencv::{
-	core::Mat,
-	videoio::{VideoCapture, VideoCaptureTrait},
+	core::{self, Mat, MatTraitConst, Size},
+	imgproc,
+	videoio::{VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst, CAP_PROP_POS_MSEC},
+};
+use std::{
+	thread::JoinHandle,
+	time::{Duration, Instant},
 };
 
+/// The width of the downscaled copy of every frame that [`FrameStats`] are
+/// computed from.
+const THUMBNAIL_WIDTH: i32 = 160;
+
+/// The most frames a memory budget can translate to. The queue allocates every
+/// slot up front, so a tiny frame size must not turn into a huge queue.
+const MAX_QUEUE_FRAMES: usize = 4096;
+
+/// Cheap luma statistics of a decoded frame, computed by the decoder from a
+/// downscaled greyscale copy of the frame before any preprocessing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameStats {
+	/// The average luma, from 0 to 255.
+	pub mean_luma: f64,
+	/// The brightest luma, from 0 to 255. As this is taken from the
+	/// downscaled copy, tiny bright details are averaged out.
+	pub max_luma: f64,
+	/// The mean absolute luma difference to the previous frame, from 0 to
+	/// 255, or `None` for the first frame.
+	pub difference: Option<f64>,
+}
+
+impl FrameStats {
+	/// Whether this frame starts a new shot, judging by how much it differs
+	/// from the previous frame.
+	#[inline]
+	pub fn is_scene_cut(&self, threshold: f64) -> bool {
+		self.difference
+			.is_some_and(|difference| difference >= threshold)
+	}
+}
+
+/// Computes [`FrameStats`], keeping the previous frame's thumbnail around for
+/// the difference.
+#[derive(Default)]
+struct StatsTracker {
+	small: Mat,
+	thumbnail: Mat,
+	previous: Mat,
+	diff: Mat,
+}
+
+impl StatsTracker {
+	fn update(&mut self, raw_frame: &Mat) -> Result<FrameStats> {
+		let size = raw_frame.size().wrap_err("failed to get frame size")?;
+		let height = (i64::from(size.height) * i64::from(THUMBNAIL_WIDTH)
+			/ i64::from(size.width.max(1)))
+		.max(1) as i32;
+		imgproc::resize(
+			raw_frame,
+			&mut self.small,
+			Size::new(THUMBNAIL_WIDTH, height),
+			0.0,
+			0.0,
+			imgproc::INTER_AREA,
+		)
+		.wrap_err("failed to downscale frame")?;
+		imgproc::cvt_color(&self.small, &mut self.thumbnail, imgproc::COLOR_BGR2GRAY, 0)
+			.wrap_err("failed to convert thumbnail to greyscale")?;
+
+		let mean_luma = core::mean(&self.thumbnail, &core::no_array())
+			.wrap_err("failed to calculate mean luma")?[0];
+		let mut max_luma = 0.0;
+		core::min_max_loc(
+			&self.thumbnail,
+			None,
+			Some(&mut max_luma),
+			None,
+			None,
+			&core::no_array(),
+		)
+		.wrap_err("failed to calculate max luma")?;
+		let difference = if self.previous.empty() {
+			None
+		} else {
+			core::absdiff(&self.thumbnail, &self.previous, &mut self.diff)
+				.wrap_err("failed to diff against previous frame")?;
+			Some(
+				core::mean(&self.diff, &core::no_array())
+					.wrap_err("failed to calculate mean difference")?[0],
+			)
+		};
+		std::mem::swap(&mut self.thumbnail, &mut self.previous);
+
+		Ok(FrameStats {
+			mean_luma,
+			max_luma,
+			difference,
+		})
+	}
+}
+
 pub struct Frame {
 	index: usize,
 	frame: Mat,
+	stats: FrameStats,
 }
 
 impl Frame {
@@ -25,6 +129,11 @@ impl Frame {
 		&self.frame
 	}
 
+	#[inline]
+	pub fn stats(&self) -> &FrameStats {
+		&self.stats
+	}
+
 	#[inline]
 	pub fn into_frame(self) -> Mat {
 		self.frame
@@ -33,24 +142,178 @@ impl Frame {
 
 pub type FrameSender = Sender<Frame>;
 pub type FrameReceiver = Receiver<Frame>;
-pub type MatchedFrameSender = Sender<usize>;
-pub type MatchedFrameReceiver = Receiver<usize>;
+/// Carries the detection of every scanned frame back from the workers.
+pub type DetectionSender = Sender<(usize, Detection)>;
+pub type DetectionReceiver = Receiver<(usize, Detection)>;
+
+/// The worker threads spawned for a single scan.
+///
+/// If any worker fails, it cancels the pool's token so that the decoder and
+/// the remaining workers stop as soon as possible.
+pub struct WorkerPool {
+	workers: Vec<JoinHandle<Result<usize>>>,
+	cancel: CancellationToken,
+}
 
-pub fn send_frames(capture: &mut VideoCapture, frame_sender: FrameSender) -> Result<()> {
-	let mut index = 1_usize;
+impl WorkerPool {
+	pub(crate) fn new(cancel: CancellationToken) -> Self {
+		Self {
+			workers: Vec::new(),
+			cancel,
+		}
+	}
+
+	pub(crate) fn push(&mut self, worker: JoinHandle<Result<usize>>) {
+		self.workers.push(worker);
+	}
+
+	/// The token shared by every worker in this pool.
+	#[inline]
+	pub fn cancellation_token(&self) -> &CancellationToken {
+		&self.cancel
+	}
+
+	/// Waits for every worker to exit, returning the total amount of frames
+	/// processed, or the first error any worker ran into.
+	pub fn join(self) -> Result<usize> {
+		let mut frames_processed = 0;
+		let mut first_error = None;
+		for worker in self.workers {
+			let result = worker
+				.join()
+				.unwrap_or_else(|_| Err(eyre!("worker thread panicked")));
+			match result {
+				Ok(processed) => frames_processed += processed,
+				Err(err) => {
+					self.cancel.cancel();
+					first_error.get_or_insert(err);
+				}
+			}
+		}
+		match first_error {
+			Some(err) => Err(err),
+			None => Ok(frames_processed),
+		}
+	}
+}
+
+/// How many decoded frames may be waiting for a worker at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueLimit {
+	/// A fixed amount of frames.
+	Frames(usize),
+	/// A memory budget in megabytes, converted to a frame count based on the
+	/// size of a single decoded frame, up to 4096 frames. This needs the
+	/// video to report its frame size.
+	Megabytes(usize),
+}
+
+impl Default for QueueLimit {
+	fn default() -> Self {
+		Self::Frames(64)
+	}
+}
+
+impl QueueLimit {
+	/// The queue capacity in frames, given the size in bytes of one frame,
+	/// which is 0 if it isn't known.
+	pub fn capacity(&self, frame_bytes: usize) -> Result<usize> {
+		match *self {
+			Self::Frames(frames) => Ok(frames.max(1)),
+			Self::Megabytes(_) if frame_bytes == 0 => Err(eyre!(
+				"the video doesn't report its frame size, so the frame queue can't be limited by \
+				 memory"
+			)),
+			Self::Megabytes(megabytes) => Ok(
+				(megabytes.saturating_mul(1024 * 1024) / frame_bytes).clamp(1, MAX_QUEUE_FRAMES)
+			),
+		}
+	}
+}
+
+/// Statistics about the frame queue between the decoder and the workers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+	pub frames_decoded: usize,
+	pub capacity: usize,
+	/// The most frames that were ever waiting in the queue at once.
+	pub peak_depth: usize,
+	pub mean_depth: f64,
+	/// How many times the decoder had to wait for the queue to have room.
+	pub stalls: usize,
+	/// The total time the decoder spent waiting for the queue to have room.
+	pub stall_time: Duration,
+}
+
+/// Decodes every frame from the capture and sends it to the worker threads,
+/// returning statistics about the frame queue. `on_frame` is called with the
+/// index, timestamp in milliseconds and [`FrameStats`] of every frame, in
+/// order, from the calling thread. Frame indices start at 0.
+///
+/// Stops early, without error, once the cancellation token is cancelled.
+pub fn send_frames(
+	capture: &mut VideoCapture,
+	frame_sender: FrameSender,
+	preprocessor: &Preprocessor,
+	observer: &dyn ProgressObserver,
+	cancel: &CancellationToken,
+	mut on_frame: impl FnMut(usize, f64, &FrameStats),
+) -> Result<QueueStats> {
+	let mut stats = QueueStats {
+		capacity: frame_sender.capacity().unwrap_or(usize::MAX),
+		..QueueStats::default()
+	};
+	let mut total_depth = 0_usize;
+	let mut index = 0_usize;
 	let mut raw_frame = Mat::default();
 	let mut mid_a = Mat::default();
 	let mut mid_b = Mat::default();
-	while capture
-		.read(&mut raw_frame)
-		.wrap_err_with(|| format!("failed to read frame {index} from video capture input"))?
+	let mut tracker = StatsTracker::default();
+	while !cancel.is_cancelled()
+		&& capture
+			.read(&mut raw_frame)
+			.wrap_err_with(|| format!("failed to read frame {index} from video capture input"))?
 	{
-		let frame = crate::fixup::fixup_frame(&raw_frame, &mut mid_a, &mut mid_b, true)
+		let frame_stats = tracker
+			.update(&raw_frame)
+			.wrap_err_with(|| format!("failed to calculate stats of frame {index}"))?;
+		let millis = capture
+			.get(CAP_PROP_POS_MSEC)
+			.wrap_err_with(|| format!("failed to get timestamp of frame {index}"))?;
+		on_frame(index, millis, &frame_stats);
+		let frame = crate::fixup::fixup_frame(&raw_frame, &mut mid_a, &mut mid_b, preprocessor)
 			.wrap_err_with(|| format!("failed to fixup image from frame {index}"))?;
-		frame_sender
-			.send(Frame { index, frame })
-			.map_err(|_| eyre!("failed to send frame {index} to worker threads"))?;
+		let sent = match frame_sender.try_send(Frame {
+			index,
+			frame,
+			stats: frame_stats,
+		}) {
+			Ok(()) => Ok(()),
+			Err(TrySendError::Full(frame)) => {
+				let stall_start = Instant::now();
+				let sent = frame_sender.send(frame).map_err(|_| ());
+				stats.stalls += 1;
+				stats.stall_time += stall_start.elapsed();
+				sent
+			}
+			Err(TrySendError::Disconnected(_)) => Err(()),
+		};
+		if sent.is_err() {
+			// The workers stop on their own when cancelled, so this isn't an error.
+			if cancel.is_cancelled() {
+				break;
+			}
+			return Err(eyre!("failed to send frame {index} to worker threads"));
+		}
+		let depth = frame_sender.len();
+		stats.peak_depth = stats.peak_depth.max(depth);
+		total_depth += depth;
+		observer.on_progress(ProgressEvent::FrameDecoded { index });
 		index += 1;
 	}
-	Ok(())
+	stats.frames_decoded = index;
+	if stats.frames_decoded > 0 {
+		stats.mean_depth = total_depth as f64 / stats.frames_decoded as f64;
+	}
+	Ok(stats)
 }
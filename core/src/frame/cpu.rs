@@ -1,150 +1,700 @@
 #![allow(clippy::too_many_arguments)]
 use crate::{
-	frame::{Frame, FrameReceiver, MatchedFrameSender},
-	FRAMES_PROCESSED,
+	cancel::CancellationToken,
+	detect::{Detection, Detector},
+	fixup::Step,
+	frame::{DetectionSender, Frame, FrameReceiver, WorkerPool},
+	progress::{ProgressEvent, ProgressObserver},
+	templates::Template,
 };
 use color_eyre::eyre::{eyre, ContextCompat, Result, WrapErr};
 use opencv::{
-	core::{self, Mat, Rect},
+	core::{self, Mat, MatTraitConst, Point, Rect, Scalar, Size},
 	imgproc,
 };
-use std::{sync::atomic::Ordering, thread};
+use std::{ops::RangeInclusive, str::FromStr, sync::Arc, thread};
 
-fn basic_match(result: &mut Mat, frame: &Mat, template: &Mat) -> Result<f64> {
-	let mut max_val: f64 = 0.0;
-	imgproc::match_template(
-		frame,
-		template,
-		result,
-		imgproc::TM_CCOEFF_NORMED,
-		&core::no_array(),
-	)
+/// The OpenCV template matching method, see [`imgproc::match_template`].
+///
+/// Scores are flipped where needed so higher is always better, but each
+/// method still spreads them differently, so each has its own threshold
+/// scale. See [`MatchMethod::default_threshold`]. Thresholds that unrelated
+/// content would already reach on a method's scale, as happens when they were
+/// written for another method, are rejected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchMethod {
+	/// Normalized correlation coefficient, from -1 to 1. Unrelated content
+	/// scores around 0.
+	#[default]
+	CcoeffNormed,
+	/// Normalized cross-correlation, from 0 to 1. Pixels are never negative,
+	/// so even unrelated content usually scores above 0.8.
+	CcorrNormed,
+	/// Normalized squared difference, from 0 to 1, reported as `1 - difference`
+	/// so that 1 is a perfect match. Unrelated content usually scores above
+	/// 0.5.
+	SqdiffNormed,
+}
+
+impl MatchMethod {
+	/// The range every score of this method falls in.
+	pub fn score_range(&self) -> RangeInclusive<f64> {
+		match self {
+			Self::CcoeffNormed => -1.0..=1.0,
+			Self::CcorrNormed | Self::SqdiffNormed => 0.0..=1.0,
+		}
+	}
+
+	/// A reasonable starting threshold on this method's scale, used when
+	/// none is given.
+	pub fn default_threshold(&self) -> f64 {
+		match self {
+			Self::CcoeffNormed => 0.7,
+			Self::CcorrNormed => 0.97,
+			Self::SqdiffNormed => 0.9,
+		}
+	}
+
+	/// Roughly what unrelated content scores with this method. Thresholds at
+	/// or below this would match nearly every frame.
+	pub fn unrelated_score(&self) -> f64 {
+		match self {
+			Self::CcoeffNormed => 0.0,
+			Self::CcorrNormed => 0.8,
+			Self::SqdiffNormed => 0.5,
+		}
+	}
+
+	/// Checks that a threshold can be reached, but not by unrelated content,
+	/// on this method's scale.
+	pub fn check_threshold(&self, threshold: f64) -> Result<()> {
+		let range = self.score_range();
+		if !(threshold > *range.start() && threshold <= *range.end()) {
+			return Err(eyre!(
+				"threshold {threshold} is outside of the score range of {self:?} ({} to {})",
+				range.start(),
+				range.end()
+			));
+		}
+		if threshold <= self.unrelated_score() {
+			return Err(eyre!(
+				"threshold {threshold} would match unrelated content with {self:?}, which already \
+				 scores around {}. Thresholds aren't comparable between methods, the default for \
+				 this one is {}",
+				self.unrelated_score(),
+				self.default_threshold()
+			));
+		}
+		Ok(())
+	}
+
+	fn opencv_method(&self) -> i32 {
+		match self {
+			Self::CcoeffNormed => imgproc::TM_CCOEFF_NORMED,
+			Self::CcorrNormed => imgproc::TM_CCORR_NORMED,
+			Self::SqdiffNormed => imgproc::TM_SQDIFF_NORMED,
+		}
+	}
+}
+
+impl FromStr for MatchMethod {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+			"ccoeff_normed" | "ccoeff" => Ok(Self::CcoeffNormed),
+			"ccorr_normed" | "ccorr" => Ok(Self::CcorrNormed),
+			"sqdiff_normed" | "sqdiff" => Ok(Self::SqdiffNormed),
+			_ => Err(eyre!(
+				"unknown match method '{s}', expected ccoeff_normed, ccorr_normed, or \
+				 sqdiff_normed"
+			)),
+		}
+	}
+}
+
+/// Matches the template against the frame, returning the best score, where
+/// higher is always better regardless of the method used, and its location.
+/// Masked templates only compare their opaque pixels. If no position gives a
+/// valid score, the score is negative infinity so it never matches.
+fn basic_match(
+	result: &mut Mat,
+	frame: &Mat,
+	template: &Mat,
+	mask: Option<&Mat>,
+	method: MatchMethod,
+) -> Result<(f64, Point)> {
+	match mask {
+		Some(mask) => {
+			imgproc::match_template(frame, template, result, method.opencv_method(), mask)
+		}
+		None => imgproc::match_template(
+			frame,
+			template,
+			result,
+			method.opencv_method(),
+			&core::no_array(),
+		),
+	}
 	.wrap_err("template matching failed")?;
+	// Masks can divide by zero on flat regions of the frame, which leaves
+	// infinities and NaNs in the result. Normalized scores never go far
+	// outside of -1..1, so anything else is ignored.
+	let mut valid = Mat::default();
+	core::in_range(result, &Scalar::all(-2.0), &Scalar::all(2.0), &mut valid)
+		.wrap_err("failed to find valid template matching results")?;
+	if core::count_non_zero(&valid).wrap_err("failed to count valid results")? == 0 {
+		return Ok((f64::NEG_INFINITY, Point::default()));
+	}
+	let mut min_val: f64 = 0.0;
+	let mut max_val: f64 = 0.0;
+	let mut min_loc = Point::default();
+	let mut max_loc = Point::default();
 	core::min_max_loc(
 		result,
-		None,
+		Some(&mut min_val),
 		Some(&mut max_val),
-		None,
-		None,
-		&core::no_array(),
+		Some(&mut min_loc),
+		Some(&mut max_loc),
+		&valid,
 	)
-	.wrap_err("calculating global maximum value failed")?;
-	Ok(max_val)
+	.wrap_err("calculating global extremes failed")?;
+	Ok(match method {
+		MatchMethod::SqdiffNormed => (1.0 - min_val, min_loc),
+		_ => (max_val, max_loc),
+	})
+}
+
+/// A template resized by a given factor for multi-scale matching.
+#[derive(Clone)]
+pub struct ScaledTemplate {
+	pub scale: f64,
+	/// The index of the template this was scaled from, which every scale of
+	/// the same template shares.
+	pub source: usize,
+	pub template: Template,
+}
+
+/// Resizes every template by every scale factor. The scales of a template
+/// are kept next to each other.
+pub fn scale_templates(templates: &[Template], scales: &[f64]) -> Result<Vec<ScaledTemplate>> {
+	let mut scaled = Vec::with_capacity(templates.len() * scales.len());
+	for (source, template) in templates.iter().enumerate() {
+		for &scale in scales {
+			if (scale - 1.0).abs() < f64::EPSILON {
+				scaled.push(ScaledTemplate {
+					scale,
+					source,
+					template: template.clone(),
+				});
+				continue;
+			}
+			let interpolation = if scale < 1.0 {
+				imgproc::INTER_AREA
+			} else {
+				imgproc::INTER_LINEAR
+			};
+			let mut image = Mat::default();
+			imgproc::resize(
+				&template.image,
+				&mut image,
+				Size::default(),
+				scale,
+				scale,
+				interpolation,
+			)
+			.wrap_err_with(|| format!("failed to scale template by {scale}"))?;
+			let mask = match &template.mask {
+				Some(mask) => {
+					let mut scaled_mask = Mat::default();
+					// Nearest neighbour keeps the mask binary.
+					imgproc::resize(
+						mask,
+						&mut scaled_mask,
+						image
+							.size()
+							.wrap_err("failed to get scaled template size")?,
+						0.0,
+						0.0,
+						imgproc::INTER_NEAREST,
+					)
+					.wrap_err_with(|| format!("failed to scale template mask by {scale}"))?;
+					Some(scaled_mask)
+				}
+				None => None,
+			};
+			scaled.push(ScaledTemplate {
+				scale,
+				source,
+				template: Template {
+					image,
+					mask,
+					..template.clone()
+				},
+			});
+		}
+	}
+	Ok(scaled)
 }
 
-fn process_frame(
+/// Matches a single template against the frame, cropped to the template's own
+/// region of interest if it has one. Returns `None` if the template is larger
+/// than the region it would be matched in, otherwise the score and where in
+/// the frame the template was found.
+fn match_scaled(
 	bounds: Option<&Rect>,
+	method: MatchMethod,
 	result: &mut Mat,
-	frame: Mat,
-	pos_templates: &[Mat],
-	neg_templates: &[Mat],
-	pos_threshold: Option<f64>,
-	neg_threshold: Option<f64>,
-) -> Result<(bool, f64, f64)> {
-	let frame = match bounds {
+	frame: &Mat,
+	template: &Template,
+) -> Result<Option<(f64, Point)>> {
+	let roi;
+	let (frame, offset) = match template.bounds.as_ref().or(bounds) {
 		Some(bounds) => {
-			Mat::roi(&frame, *bounds).wrap_err_with(|| format!("invalid roi: {bounds:?}"))?
+			roi = Mat::roi(frame, *bounds).wrap_err_with(|| format!("invalid roi: {bounds:?}"))?;
+			(&roi, bounds.tl())
 		}
-		None => frame,
+		None => (frame, Point::default()),
 	};
+	if template.image.rows() > frame.rows() || template.image.cols() > frame.cols() {
+		return Ok(None);
+	}
+	let (score, location) = basic_match(
+		result,
+		frame,
+		&template.image,
+		template.mask.as_ref(),
+		template.method.unwrap_or(method),
+	)
+	.wrap_err_with(|| format!("failed to match template {}", template.label))?;
+	Ok(Some((score, location + offset)))
+}
 
-	let mut pos: f64 = 0.0;
-	let mut neg: f64 = 0.0;
-	let mut matched = false;
-	for template in pos_templates {
-		pos = pos.max(basic_match(result, &frame, template)?);
-		match (pos_threshold, neg_threshold) {
-			(Some(pos_threshold), None) if pos >= pos_threshold => return Ok((true, pos, 0.0)),
-			(Some(pos_threshold), _) if pos >= pos_threshold => {
-				matched = true;
-				break;
-			}
-			_ => {}
+/// The threshold a template has to reach. Templates that override the method
+/// but not the threshold use their method's default threshold, since the
+/// given one is on another method's scale.
+pub fn template_threshold(
+	template: &Template,
+	method: MatchMethod,
+	threshold: Option<f64>,
+) -> Option<f64> {
+	match (template.threshold, template.method) {
+		(Some(own), _) => Some(own),
+		(None, Some(own)) if own != method => threshold.map(|_| own.default_threshold()),
+		(None, _) => threshold,
+	}
+}
+
+/// Checks that every template's threshold is on the scale of the method it
+/// is matched with.
+pub fn check_thresholds(
+	templates: &[ScaledTemplate],
+	method: MatchMethod,
+	threshold: Option<f64>,
+) -> Result<()> {
+	for ScaledTemplate { template, .. } in templates {
+		if let Some(threshold) = template_threshold(template, method, threshold) {
+			template
+				.method
+				.unwrap_or(method)
+				.check_threshold(threshold)
+				.wrap_err_with(|| format!("invalid threshold for template {}", template.label))?;
 		}
 	}
+	Ok(())
+}
 
-	if matched || pos_threshold.is_none() {
-		for template in neg_templates {
-			neg = neg.max(basic_match(result, &frame, template)?);
-			match neg_threshold {
-				Some(neg_threshold) if neg >= neg_threshold => return Ok((false, pos, neg)),
+/// Scores the frame against every template. Per-template thresholds, regions
+/// of interest and methods take priority over the ones passed in here.
+///
+/// Scores are negative infinity if no template could be scored, such as when
+/// they're all larger than the frame.
+///
+/// Scoring normally stops as soon as the outcome is settled. With `score_all`,
+/// every template is scored instead, so the scores are the best over all of
+/// them: a matched frame reports the best template that matched, rather than
+/// the first one.
+pub fn process_frame(
+	bounds: Option<&Rect>,
+	method: MatchMethod,
+	result: &mut Mat,
+	frame: &Mat,
+	pos_templates: &[ScaledTemplate],
+	neg_templates: &[ScaledTemplate],
+	pos_threshold: Option<f64>,
+	neg_threshold: Option<f64>,
+	exit_threshold: Option<f64>,
+	score_all: bool,
+) -> Result<Detection> {
+	let mut detection = Detection {
+		pos_score: f64::NEG_INFINITY,
+		neg_score: f64::NEG_INFINITY,
+		..Detection::default()
+	};
+	// Once a template matches, its remaining scales are still tried so the
+	// best matching scale is reported.
+	let mut matched_source = None;
+	for ScaledTemplate {
+		scale,
+		source,
+		template,
+	} in pos_templates
+	{
+		if !score_all && matched_source.is_some_and(|matched| matched != *source) {
+			break;
+		}
+		let Some((score, location)) = match_scaled(bounds, method, result, frame, template)? else {
+			continue;
+		};
+		let passed = template_threshold(template, method, pos_threshold)
+			.is_some_and(|threshold| score >= threshold);
+		// Thresholds differ per template, so the template that matched is
+		// reported even if another one scored higher without matching.
+		let replaces = match matched_source {
+			Some(_) => passed && score > detection.pos_score,
+			None => passed || score > detection.pos_score,
+		};
+		if replaces {
+			detection.pos_score = score;
+			detection.scale = Some(*scale);
+			detection.pos_label = Some(template.label.clone());
+			detection.location = Some(location);
+		}
+		if passed && matched_source.is_none() {
+			detection.matched = true;
+			matched_source = Some(*source);
+		}
+	}
+
+	detection.sustains = exit_threshold.is_some_and(|exit| detection.pos_score >= exit);
+
+	let flagged = detection.matched || detection.sustains;
+	if flagged || pos_threshold.is_none() || score_all {
+		for ScaledTemplate { template, .. } in neg_templates {
+			let Some((score, _)) = match_scaled(bounds, method, result, frame, template)? else {
+				continue;
+			};
+			if score > detection.neg_score {
+				detection.neg_score = score;
+				detection.neg_label = Some(template.label.clone());
+			}
+			match template_threshold(template, method, neg_threshold) {
+				Some(threshold) if score >= threshold && flagged => {
+					detection.matched = false;
+					detection.sustains = false;
+					detection.vetoed = true;
+					if !score_all {
+						return Ok(detection);
+					}
+				}
 				_ => {}
 			}
 		}
 	}
 
-	Ok((matched, pos, neg))
+	Ok(detection)
 }
 
-fn worker_thread(
-	bounds: Option<Rect>,
-	pos_templates: Vec<Mat>,
-	neg_templates: Vec<Mat>,
+/// The default [`Detector`]: the best normalized correlation over the positive
+/// templates, vetoed by any negative template.
+#[derive(Clone)]
+pub struct TemplateDetector {
+	pos_templates: Vec<Template>,
+	neg_templates: Vec<Template>,
+	pos_scaled: Vec<ScaledTemplate>,
+	neg_scaled: Vec<ScaledTemplate>,
+	scales: Vec<f64>,
+	template_height: Option<i32>,
 	pos_threshold: Option<f64>,
 	neg_threshold: Option<f64>,
-	frame_receiver: FrameReceiver,
-	result_sender: MatchedFrameSender,
-) -> Result<()> {
-	let mut result = Mat::default();
-	for Frame { index, frame } in frame_receiver.iter() {
-		let (result, ..) = process_frame(
-			bounds.as_ref(),
-			&mut result,
-			frame,
-			&pos_templates,
-			&neg_templates,
-			pos_threshold,
-			neg_threshold,
+	exit_threshold: Option<f64>,
+	score_all: bool,
+	bounds: Option<Rect>,
+	method: MatchMethod,
+	result: Mat,
+}
+
+impl TemplateDetector {
+	pub fn new(pos_templates: Vec<Template>, neg_templates: Vec<Template>) -> Self {
+		Self {
+			pos_templates,
+			neg_templates,
+			pos_scaled: Vec::new(),
+			neg_scaled: Vec::new(),
+			scales: vec![1.0],
+			template_height: None,
+			pos_threshold: Some(0.7),
+			neg_threshold: Some(0.7),
+			exit_threshold: None,
+			score_all: false,
+			bounds: None,
+			method: MatchMethod::default(),
+			result: Mat::default(),
+		}
+	}
+
+	/// The minimum match threshold for positive templates, on the method's
+	/// scale.
+	pub fn pos_threshold(mut self, threshold: Option<f64>) -> Self {
+		self.pos_threshold = threshold;
+		self
+	}
+
+	/// The minimum match threshold for negative templates, on the method's
+	/// scale.
+	pub fn neg_threshold(mut self, threshold: Option<f64>) -> Self {
+		self.neg_threshold = threshold;
+		self
+	}
+
+	/// The score on the method's scale that frames which didn't match still
+	/// need to reach to keep an already started run going. Templates that
+	/// override the method can't be combined with an exit threshold.
+	pub fn exit_threshold(mut self, threshold: Option<f64>) -> Self {
+		self.exit_threshold = threshold;
+		self
+	}
+
+	/// Whether to score every template on every frame, rather than stopping as
+	/// soon as the outcome is settled, so the recorded scores are the best
+	/// ones. This is slower, and is meant for tuning thresholds.
+	pub fn score_all(mut self, score_all: bool) -> Self {
+		self.score_all = score_all;
+		self
+	}
+
+	/// The region of interest to match templates in.
+	pub fn bounds(mut self, bounds: Option<Rect>) -> Self {
+		self.bounds = bounds;
+		self
+	}
+
+	/// The template matching method, unless a template overrides it. Scores
+	/// are flipped so that higher is always better, but thresholds have to be
+	/// on the method's scale, see [`MatchMethod::default_threshold`].
+	pub fn method(mut self, method: MatchMethod) -> Self {
+		self.method = method;
+		self
+	}
+
+	/// The scale factors to try every template at. Defaults to only the
+	/// template's native size.
+	pub fn scales(mut self, scales: Vec<f64>) -> Self {
+		self.scales = scales;
+		self
+	}
+
+	/// The height of the video the templates were cut from. When set, the
+	/// scale factors are relative to the ratio between the input video's
+	/// height and this.
+	pub fn template_height(mut self, height: Option<i32>) -> Self {
+		self.template_height = height;
+		self
+	}
+}
+
+impl Detector for TemplateDetector {
+	fn prepare(&mut self, frame_size: Size) -> Result<()> {
+		let base = match self.template_height {
+			Some(height) if height > 0 => f64::from(frame_size.height) / f64::from(height),
+			_ => 1.0,
+		};
+		let scales = self
+			.scales
+			.iter()
+			.map(|scale| scale * base)
+			.collect::<Vec<_>>();
+		self.pos_scaled = scale_templates(&self.pos_templates, &scales)
+			.wrap_err("failed to scale positive templates")?;
+		self.neg_scaled = scale_templates(&self.neg_templates, &scales)
+			.wrap_err("failed to scale negative templates")?;
+		check_thresholds(&self.pos_scaled, self.method, self.pos_threshold)?;
+		check_thresholds(&self.neg_scaled, self.method, self.neg_threshold)?;
+		if let Some(threshold) = self.exit_threshold {
+			self.method
+				.check_threshold(threshold)
+				.wrap_err("invalid exit threshold")?;
+			// Any positive template's score can sustain a run, so they all have
+			// to be on the exit threshold's scale.
+			if let Some(template) = self
+				.pos_templates
+				.iter()
+				.find(|template| template.method.is_some_and(|method| method != self.method))
+			{
+				return Err(eyre!(
+					"the exit threshold is on the scale of {:?}, but template {} uses {:?}",
+					self.method,
+					template.label,
+					template.method.unwrap_or_default()
+				));
+			}
+		}
+		Ok(())
+	}
+
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		process_frame(
+			self.bounds.as_ref(),
+			self.method,
+			&mut self.result,
+			frame.frame(),
+			&self.pos_scaled,
+			&self.neg_scaled,
+			self.pos_threshold,
+			self.neg_threshold,
+			self.exit_threshold,
+			self.score_all,
 		)
-		.wrap_err_with(|| format!("failed to process frame {index} on cpu"))?;
-		if result {
-			result_sender.send(index).map_err(|_| {
-				eyre!("failed to send result for frame {index} back to main thread")
-			})?;
+	}
+}
+
+/// Matches templates on edge maps rather than raw intensities, which holds up
+/// better for translucent overlays drawn over changing backgrounds.
+///
+/// The edge step is applied to the scaled templates once, and to the region
+/// of interest of every frame, so the rest of the frame is never processed.
+#[derive(Clone)]
+pub struct EdgeDetector {
+	inner: TemplateDetector,
+	edges: Step,
+	/// Whether any template has its own region of interest, in which case the
+	/// whole frame has to be converted.
+	template_bounds: bool,
+	edge_frame: Mat,
+}
+
+impl EdgeDetector {
+	/// Wraps a template detector, matching on the output of the given edge
+	/// step (usually [`Step::Canny`] or [`Step::Sobel`]).
+	pub fn new(inner: TemplateDetector, edges: Step) -> Self {
+		let template_bounds = inner
+			.pos_templates
+			.iter()
+			.chain(&inner.neg_templates)
+			.any(|template| template.bounds.is_some());
+		Self {
+			inner,
+			edges,
+			template_bounds,
+			edge_frame: Mat::default(),
 		}
-		FRAMES_PROCESSED.fetch_add(1, Ordering::Relaxed);
 	}
-	Ok(())
 }
 
-pub fn spawn_threads(
+impl Detector for EdgeDetector {
+	fn prepare(&mut self, frame_size: Size) -> Result<()> {
+		self.inner.prepare(frame_size)?;
+		// Edges are found after scaling so they stay one pixel wide.
+		for scaled in self
+			.inner
+			.pos_scaled
+			.iter_mut()
+			.chain(self.inner.neg_scaled.iter_mut())
+		{
+			let template = &mut scaled.template;
+			let mut edges = Mat::default();
+			self.edges
+				.apply(&template.image, &mut edges)
+				.wrap_err_with(|| format!("failed to find edges of template {}", template.label))?;
+			template.image = edges;
+		}
+		Ok(())
+	}
+
+	fn score(&mut self, frame: &Frame) -> Result<Detection> {
+		let frame = frame.frame();
+		let (bounds, offset) = match self.inner.bounds {
+			Some(bounds) if !self.template_bounds => {
+				let roi =
+					Mat::roi(frame, bounds).wrap_err_with(|| format!("invalid roi: {bounds:?}"))?;
+				self.edges
+					.apply(&roi, &mut self.edge_frame)
+					.wrap_err("failed to find edges of frame")?;
+				// The edge map already only covers the region of interest.
+				(None, bounds.tl())
+			}
+			bounds => {
+				self.edges
+					.apply(frame, &mut self.edge_frame)
+					.wrap_err("failed to find edges of frame")?;
+				(bounds, Point::default())
+			}
+		};
+		let mut detection = process_frame(
+			bounds.as_ref(),
+			self.inner.method,
+			&mut self.inner.result,
+			&self.edge_frame,
+			&self.inner.pos_scaled,
+			&self.inner.neg_scaled,
+			self.inner.pos_threshold,
+			self.inner.neg_threshold,
+			self.inner.exit_threshold,
+			self.inner.score_all,
+		)?;
+		detection.location = detection.location.map(|location| location + offset);
+		Ok(detection)
+	}
+}
+
+fn worker_thread<D: Detector>(
+	mut detector: D,
+	frame_receiver: FrameReceiver,
+	result_sender: DetectionSender,
+	observer: Arc<dyn ProgressObserver>,
+	cancel: &CancellationToken,
+) -> Result<usize> {
+	let mut frames_processed = 0;
+	for frame in frame_receiver.iter() {
+		if cancel.is_cancelled() {
+			break;
+		}
+		let index = frame.index();
+		let detection = detector
+			.score(&frame)
+			.wrap_err_with(|| format!("failed to process frame {index} on cpu"))?;
+		let matched = detection.matched;
+		result_sender
+			.send((index, detection))
+			.map_err(|_| eyre!("failed to send result for frame {index} back to main thread"))?;
+		observer.on_progress(ProgressEvent::FrameProcessed { index, matched });
+		frames_processed += 1;
+	}
+	Ok(frames_processed)
+}
+
+pub fn spawn_threads<D: Detector + Clone>(
 	max_threads: Option<usize>,
-	bounds: Option<Rect>,
-	pos_templates: &[Mat],
-	neg_templates: &[Mat],
-	pos_threshold: Option<f64>,
-	neg_threshold: Option<f64>,
+	detector: &D,
 	frame_receiver: FrameReceiver,
-	result_sender: MatchedFrameSender,
-) -> Result<()> {
+	result_sender: DetectionSender,
+	observer: Arc<dyn ProgressObserver>,
+	cancel: &CancellationToken,
+) -> Result<WorkerPool> {
+	let mut pool = WorkerPool::new(cancel.clone());
 	let core_ids = core_affinity::get_core_ids().wrap_err("failed to get CPU core IDs")?;
 	for id in core_ids
 		.iter()
 		.copied()
 		.take(max_threads.unwrap_or(usize::MAX))
 	{
-		let pos_templates = pos_templates.to_vec();
-		let neg_templates = neg_templates.to_vec();
+		let detector = detector.clone();
 		let frame_receiver = frame_receiver.clone();
 		let result_sender = result_sender.clone();
-		thread::Builder::new()
+		let observer = observer.clone();
+		let cancel = cancel.clone();
+		let worker = thread::Builder::new()
 			.name(format!("cpu worker core {}", id.id))
 			.spawn(move || {
 				if !core_affinity::set_for_current(id) {
 					eprintln!("failed to set thread affinity for core {}", id.id);
 				}
-				worker_thread(
-					bounds,
-					pos_templates,
-					neg_templates,
-					pos_threshold,
-					neg_threshold,
-					frame_receiver,
-					result_sender,
-				)
-				.expect("cpu worker thread errored");
+				worker_thread(detector, frame_receiver, result_sender, observer, &cancel)
+					// Stop the decoder and the other workers, this scan is a lost cause.
+					.inspect_err(|_| cancel.cancel())
 			})
 			.wrap_err_with(|| format!("failed to spawn cpu worker on core {}", id.id))?;
+		pool.push(worker);
 	}
-	Ok(())
+	Ok(pool)
 }
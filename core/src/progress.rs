@@ -0,0 +1,48 @@
+use crossbeam_channel::Sender;
+
+/// The stage of the pipeline a [`ScrubJob`](crate::scrub::ScrubJob) is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+	/// Frames are being decoded and matched against templates.
+	Scanning,
+	/// Matched frames are being turned into time ranges.
+	Segmenting,
+	/// The kept segments are being written to the output video.
+	Splicing,
+	Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+	Phase(Phase),
+	/// A frame was decoded and queued for the worker threads.
+	FrameDecoded {
+		index: usize,
+	},
+	/// A worker thread finished matching a frame.
+	FrameProcessed {
+		index: usize,
+		matched: bool,
+	},
+}
+
+/// Receives progress events from a single job.
+///
+/// Events are sent from the decoder and from every worker thread, so
+/// implementations should be cheap and must be thread-safe.
+pub trait ProgressObserver: Send + Sync {
+	fn on_progress(&self, event: ProgressEvent);
+}
+
+impl ProgressObserver for () {
+	#[inline]
+	fn on_progress(&self, _event: ProgressEvent) {}
+}
+
+impl ProgressObserver for Sender<ProgressEvent> {
+	#[inline]
+	fn on_progress(&self, event: ProgressEvent) {
+		// The receiving end going away shouldn't stop the scan.
+		let _ = self.send(event);
+	}
+}
@@ -0,0 +1,145 @@
+use crate::detect::Detection;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+use std::{
+	fs::File,
+	io::{BufWriter, Write},
+	path::Path,
+	str::FromStr,
+};
+
+/// The scores of a single scanned frame, for plotting and threshold tuning.
+///
+/// The scores are only the best over every template if the detector was told
+/// to score them all, such as with [`TemplateDetector::score_all`]. Otherwise
+/// detectors stop scoring a frame once its outcome is settled: positive
+/// templates are scored until one matches, and negative templates only for
+/// frames that matched or sustain a run, up to the first veto. Scores are left
+/// empty if nothing could be scored.
+///
+/// [`TemplateDetector::score_all`]: crate::frame::cpu::TemplateDetector::score_all
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScoreRecord {
+	pub index: usize,
+	/// The frame's timestamp, in seconds.
+	pub timestamp: f64,
+	pub matched: bool,
+	pub pos_score: f64,
+	/// The label of the template that produced `pos_score`.
+	pub pos_template: Option<String>,
+	pub neg_score: f64,
+	/// The label of the template that produced `neg_score`.
+	pub neg_template: Option<String>,
+	/// The top-left corner of the best positive match, if the detector
+	/// locates templates.
+	pub x: Option<i32>,
+	pub y: Option<i32>,
+}
+
+impl ScoreRecord {
+	pub fn new(index: usize, timestamp: f64, detection: Detection) -> Self {
+		Self {
+			index,
+			timestamp,
+			matched: detection.matched,
+			pos_score: detection.pos_score,
+			pos_template: detection.pos_label,
+			neg_score: detection.neg_score,
+			neg_template: detection.neg_label,
+			x: detection.location.map(|location| location.x),
+			y: detection.location.map(|location| location.y),
+		}
+	}
+}
+
+/// The file format score records are exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreFormat {
+	Csv,
+	/// One JSON object per line.
+	JsonLines,
+}
+
+impl ScoreFormat {
+	/// Guesses the format from a file extension, defaulting to CSV.
+	pub fn from_path(path: &Path) -> Self {
+		match path.extension().and_then(|ext| ext.to_str()) {
+			Some("jsonl" | "ndjson" | "json") => Self::JsonLines,
+			_ => Self::Csv,
+		}
+	}
+}
+
+impl FromStr for ScoreFormat {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"csv" => Ok(Self::Csv),
+			"jsonl" | "json_lines" | "ndjson" => Ok(Self::JsonLines),
+			_ => Err(eyre!("unknown score format '{s}', expected csv or jsonl")),
+		}
+	}
+}
+
+/// Quotes a CSV field if it contains anything that would break the row.
+fn csv_field(field: &str) -> String {
+	if field.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_owned()
+	}
+}
+
+fn write_csv<W: Write>(writer: &mut W, records: &[ScoreRecord]) -> Result<()> {
+	writeln!(
+		writer,
+		"index,timestamp,matched,pos_score,pos_template,neg_score,neg_template,x,y"
+	)?;
+	let optional = |value: Option<i32>| value.map(|value| value.to_string()).unwrap_or_default();
+	// Matches JSON, which has no infinities either.
+	let score = |score: f64| {
+		if score.is_finite() {
+			format!("{score:.6}")
+		} else {
+			String::new()
+		}
+	};
+	for record in records {
+		writeln!(
+			writer,
+			"{},{:.6},{},{},{},{},{},{},{}",
+			record.index,
+			record.timestamp,
+			record.matched,
+			score(record.pos_score),
+			csv_field(record.pos_template.as_deref().unwrap_or_default()),
+			score(record.neg_score),
+			csv_field(record.neg_template.as_deref().unwrap_or_default()),
+			optional(record.x),
+			optional(record.y),
+		)?;
+	}
+	Ok(())
+}
+
+fn write_json_lines<W: Write>(writer: &mut W, records: &[ScoreRecord]) -> Result<()> {
+	for record in records {
+		serde_json::to_writer(&mut *writer, record)?;
+		writeln!(writer)?;
+	}
+	Ok(())
+}
+
+/// Writes the score records to a file in the given format.
+pub fn write_scores(path: &Path, records: &[ScoreRecord], format: ScoreFormat) -> Result<()> {
+	let file = File::create(path)
+		.wrap_err_with(|| format!("failed to create score file at {}", path.display()))?;
+	let mut writer = BufWriter::new(file);
+	match format {
+		ScoreFormat::Csv => write_csv(&mut writer, records),
+		ScoreFormat::JsonLines => write_json_lines(&mut writer, records),
+	}
+	.wrap_err("failed to write score records")?;
+	writer.flush().wrap_err("failed to write score records")
+}
@@ -1,156 +1,495 @@
 use crate::{
-	frame::{self, Frame},
-	segments, templates, video,
+	audio::{self, AudioAnalysis, AudioAnalyzer},
+	cancel::CancellationToken,
+	detect::{Detection, Detector},
+	fixup::Preprocessor,
+	frame::{self, Frame, QueueLimit, QueueStats},
+	progress::{Phase, ProgressEvent, ProgressObserver},
+	scores::ScoreRecord,
+	segments::{self, FrameSpan, Padding, PaddingRules, RunRules, SceneSnap, SegmentMode},
+	timeline::{TimeRange, Timeline},
+	video,
 };
-use color_eyre::eyre::{ContextCompat, Result, WrapErr};
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use indicatif::{HumanCount, ProgressBar, ProgressState, ProgressStyle};
+use color_eyre::eyre::{eyre, ContextCompat, Result, WrapErr};
+use crossbeam_channel::{bounded, unbounded};
 use opencv::{
-	core::Mat,
-	imgcodecs::IMREAD_GRAYSCALE,
-	videoio::{VideoCapture, VideoCaptureTraitConst, CAP_FFMPEG, CAP_PROP_FRAME_COUNT},
+	core::Size,
+	videoio::{
+		VideoCapture, VideoCaptureTraitConst, CAP_FFMPEG, CAP_PROP_FPS, CAP_PROP_FRAME_COUNT,
+		CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH,
+	},
 };
-use parking_lot::Mutex;
 use std::{
-	fmt::Write,
-	sync::{
-		atomic::{AtomicBool, AtomicUsize, Ordering},
-		Arc,
-	},
+	env,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, PoisonError},
 	thread,
+	time::{Duration, Instant},
 };
-use thread_priority::{ThreadBuilderExt, ThreadPriority};
 
-pub static DONE_PROCESSING: AtomicBool = AtomicBool::new(false);
+/// Reusable configuration for the detect -> segment -> splice pipeline.
+///
+/// A single [`Scrubber`] can be used to create any number of [`ScrubJob`]s,
+/// one per input video.
+#[derive(Clone)]
+pub struct Scrubber {
+	detector: Box<dyn Detector>,
+	padding: PaddingRules,
+	threads: Option<usize>,
+	queue_limit: QueueLimit,
+	preprocessor: Preprocessor,
+	ffmpeg_options: Option<String>,
+	scene_threshold: f64,
+	snap_window: Option<f64>,
+	audio: Option<AudioAnalyzer>,
+	run_rules: RunRules,
+	mode: SegmentMode,
+}
 
-pub fn scrub(args: ScrubArgs) -> Result<()> {
-	let (frame_sender, frame_receiver) = unbounded::<Frame>();
-	let (result_sender, result_receiver) = unbounded::<usize>();
-	let exceeding_frames = Arc::new(Mutex::new(Vec::<usize>::new()));
+impl Scrubber {
+	/// Creates a scrubber that classifies frames with the given detector,
+	/// such as a [`TemplateDetector`](crate::frame::cpu::TemplateDetector).
+	pub fn new<D: Detector>(detector: D) -> Self {
+		Self {
+			detector: Box::new(detector),
+			padding: PaddingRules::new(Padding::symmetric(FrameSpan::Seconds(1.0))),
+			threads: None,
+			queue_limit: QueueLimit::default(),
+			preprocessor: Preprocessor::default(),
+			ffmpeg_options: None,
+			scene_threshold: 30.0,
+			snap_window: None,
+			audio: None,
+			run_rules: RunRules::default(),
+			mode: SegmentMode::default(),
+		}
+	}
 
-	if let Some(opts) = &args.ffmpeg_opts {
-		std::env::set_var("OPENCV_FFMPEG_CAPTURE_OPTIONS", opts);
+	/// How much to pad out removal ranges with, before and after.
+	pub fn padding(mut self, padding: Padding) -> Self {
+		self.padding.default = padding;
+		self
 	}
 
-	// Read in the video file
-	let mut capture = VideoCapture::from_file(
-		args.input
-			.to_str()
-			.wrap_err("invalid input path cannot be represented as a str")?,
-		CAP_FFMPEG,
-	)
-	.wrap_err_with(|| format!("failed to read video from {}", args.input.display()))?;
+	/// How much to pad out removal ranges flagged by the template with the
+	/// given label, instead of the default padding.
+	pub fn label_padding<S: Into<String>>(mut self, label: S, padding: Padding) -> Self {
+		self.padding.labels.insert(label.into(), padding);
+		self
+	}
 
-	let total_frames = capture
-		.get(CAP_PROP_FRAME_COUNT)
-		.wrap_err("failed to get frame count property from video")?;
+	/// How many worker threads to use. Defaults to the amount of logical
+	/// cores.
+	pub fn threads(mut self, threads: Option<usize>) -> Self {
+		self.threads = threads;
+		self
+	}
 
-	let exceeding_frames_clone = exceeding_frames.clone();
-	thread::spawn(move || {
-		for frame in result_receiver {
-			exceeding_frames_clone.lock().push(frame);
-		}
-	});
+	/// How many decoded frames may be waiting for a worker at once. Decoding
+	/// pauses while the queue is full.
+	pub fn queue_limit(mut self, limit: QueueLimit) -> Self {
+		self.queue_limit = limit;
+		self
+	}
 
-	let pos_templates = templates::load_multi(&args.pos_templates, IMREAD_GRAYSCALE)
-		.wrap_err("failed to parse positive templates")?;
-	let neg_templates = templates::load_multi(&args.neg_templates, IMREAD_GRAYSCALE)
-		.wrap_err("failed to parse negative templates")?;
+	/// How decoded frames are converted and preprocessed. This must match
+	/// the preprocessor the detector's templates were loaded with.
+	pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+		self.preprocessor = preprocessor;
+		self
+	}
 
-	if args.cuda {
-		if opencv::core::get_cuda_enabled_device_count().unwrap_or(0) <= 0 {
-			panic!("no CUDA devices found!");
-		}
-		#[cfg(feature = "cuda")]
-		{
-			println!("using CUDA");
-			frame::gpu::spawn_threads(
-				args.clone(),
-				&templates,
-				frame_receiver.clone(),
-				result_sender.clone(),
-			)
-			.wrap_err("failed to setup gpu worker threads")?;
-		}
-		#[cfg(not(feature = "cuda"))]
-		{
-			panic!("CUDA not supported!")
-		}
-	} else {
-		println!("using normal CPU");
-		frame::cpu::spawn_threads(
-			args.clone(),
-			&pos_templates,
-			&neg_templates,
-			frame_receiver.clone(),
-			result_sender.clone(),
+	/// The mean luma difference (0-255) between two consecutive frames above
+	/// which the second one counts as a scene cut.
+	pub fn scene_threshold(mut self, threshold: f64) -> Self {
+		self.scene_threshold = threshold;
+		self
+	}
+
+	/// How matched frames are grouped into runs to be removed.
+	pub fn run_rules(mut self, rules: RunRules) -> Self {
+		self.run_rules = rules;
+		self
+	}
+
+	/// Whether the flagged parts of the video are removed, or are the only
+	/// parts kept.
+	pub fn mode(mut self, mode: SegmentMode) -> Self {
+		self.mode = mode;
+		self
+	}
+
+	/// Snap the edges of removal ranges to the nearest scene cut within this
+	/// many seconds, instead of padding them.
+	pub fn snap_window(mut self, window: Option<f64>) -> Self {
+		self.snap_window = window;
+		self
+	}
+
+	/// Also removes the silences and reference clips found in the input's
+	/// audio by this analyzer. They're already exact, so unlike the flagged
+	/// frames they aren't padded. They are still reported, but never kept, in
+	/// [`SegmentMode::Keep`].
+	pub fn audio(mut self, analyzer: Option<AudioAnalyzer>) -> Self {
+		self.audio = analyzer.filter(AudioAnalyzer::is_enabled);
+		self
+	}
+
+	/// The ffmpeg options to force when opening the input video.
+	///
+	/// OpenCV only reads these from the process-wide
+	/// `OPENCV_FFMPEG_CAPTURE_OPTIONS` environment variable, so it's set just
+	/// while the input is opened and restored afterwards. Jobs are opened one
+	/// at a time, but this isn't thread-safe against anything else in the
+	/// process reading or writing the environment meanwhile.
+	pub fn ffmpeg_options(mut self, options: Option<String>) -> Self {
+		self.ffmpeg_options = options;
+		self
+	}
+
+	/// Opens the given input video, creating a job that can be scanned and
+	/// spliced.
+	pub fn job<P: AsRef<Path>>(&self, input: P) -> Result<ScrubJob> {
+		let input = input.as_ref();
+		let capture = open_capture(
+			input
+				.to_str()
+				.wrap_err("invalid input path cannot be represented as a str")?,
+			self.ffmpeg_options.as_deref(),
 		)
-		.wrap_err("failed to setup cpu worker threads")?;
+		.wrap_err_with(|| format!("failed to read video from {}", input.display()))?;
+
+		let total_frames = capture
+			.get(CAP_PROP_FRAME_COUNT)
+			.wrap_err("failed to get frame count property from video")?;
+		let fps = capture
+			.get(CAP_PROP_FPS)
+			.wrap_err("failed to read fps property from video")?;
+		let width = capture
+			.get(CAP_PROP_FRAME_WIDTH)
+			.wrap_err("failed to read frame width property from video")?;
+		let height = capture
+			.get(CAP_PROP_FRAME_HEIGHT)
+			.wrap_err("failed to read frame height property from video")?;
+		let timeline = video::probe_timeline(input, fps)
+			.wrap_err_with(|| format!("failed to probe video stream of {}", input.display()))?;
+
+		Ok(ScrubJob {
+			scrubber: self.clone(),
+			input: input.to_path_buf(),
+			capture,
+			total_frames: total_frames.round() as usize,
+			fps,
+			timeline,
+			frame_size: (width.round() as usize, height.round() as usize),
+			observer: Arc::new(()),
+			cancel: CancellationToken::new(),
+		})
+	}
+}
+
+const CAPTURE_OPTIONS_VAR: &str = "OPENCV_FFMPEG_CAPTURE_OPTIONS";
+
+/// Serializes opening captures, as the ffmpeg options are passed through the
+/// environment.
+static CAPTURE_OPTIONS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Opens a capture with the given ffmpeg options, restoring whatever options
+/// were set in the environment before afterwards.
+fn open_capture(path: &str, ffmpeg_options: Option<&str>) -> Result<VideoCapture> {
+	// Held either way, so a capture without options can't pick up another
+	// job's options while they're set.
+	let _lock = CAPTURE_OPTIONS_LOCK
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner);
+	let Some(options) = ffmpeg_options else {
+		return Ok(VideoCapture::from_file(path, CAP_FFMPEG)?);
+	};
+	let previous = env::var_os(CAPTURE_OPTIONS_VAR);
+	env::set_var(CAPTURE_OPTIONS_VAR, options);
+	let capture = VideoCapture::from_file(path, CAP_FFMPEG);
+	match previous {
+		Some(previous) => env::set_var(CAPTURE_OPTIONS_VAR, previous),
+		None => env::remove_var(CAPTURE_OPTIONS_VAR),
 	}
+	Ok(capture?)
+}
+
+/// A single input video being run through the [`Scrubber`] pipeline.
+pub struct ScrubJob {
+	scrubber: Scrubber,
+	input: PathBuf,
+	capture: VideoCapture,
+	total_frames: usize,
+	fps: f64,
+	/// The stream's time base and bounds, before any frames are decoded.
+	timeline: Timeline,
+	frame_size: (usize, usize),
+	observer: Arc<dyn ProgressObserver>,
+	cancel: CancellationToken,
+}
+
+impl ScrubJob {
+	/// Sets the observer that will receive progress events for this job.
+	pub fn with_observer<O: ProgressObserver + 'static>(mut self, observer: O) -> Self {
+		self.observer = Arc::new(observer);
+		self
+	}
+
+	/// Sets the token used to stop [`ScrubJob::scan`] early. The scan will
+	/// then return the results for the frames processed up to that point.
+	pub fn with_cancellation_token(mut self, cancel: CancellationToken) -> Self {
+		self.cancel = cancel;
+		self
+	}
+
+	#[inline]
+	pub fn input(&self) -> &Path {
+		&self.input
+	}
+
+	/// The frame count reported by the video container.
+	#[inline]
+	pub fn total_frames(&self) -> usize {
+		self.total_frames
+	}
+
+	#[inline]
+	pub fn fps(&self) -> f64 {
+		self.fps
+	}
+
+	/// Decodes the video, matches every frame against the templates, and
+	/// builds the list of segments to keep.
+	pub fn scan(&mut self) -> Result<ScrubResult> {
+		let start = Instant::now();
+		let scrubber = &self.scrubber;
+		self.observer
+			.on_progress(ProgressEvent::Phase(Phase::Scanning));
+		// Frames are converted to 8-bit greyscale or colour before being queued.
+		let (width, height) = self.frame_size;
+		let frame_bytes = width * height * scrubber.preprocessor.color_mode.channels();
+		let queue_capacity = scrubber.queue_limit.capacity(frame_bytes)?;
+		let (frame_sender, frame_receiver) = bounded::<Frame>(queue_capacity);
+		let (result_sender, result_receiver) = unbounded::<(usize, Detection)>();
+
+		let mut detector = scrubber.detector.clone();
+		detector
+			.prepare(Size::new(width as i32, height as i32))
+			.wrap_err("failed to prepare detector for video")?;
+
+		let collector = thread::Builder::new()
+			.name("detection collector".to_owned())
+			.spawn(move || result_receiver.into_iter().collect::<Vec<_>>())
+			.wrap_err("failed to spawn detection collector thread")?;
 
-	let progress_thread = thread::Builder::new()
-		.name("frame progress thread".to_owned())
-		.spawn_with_priority(ThreadPriority::Min, move |_| {
-			let total_frames = total_frames.round() as u64;
-			let progress_bar = ProgressBar::new(total_frames).with_style(
-				ProgressStyle::with_template(
-					"[{elapsed}] {wide_bar:.green/red} {pos}/{len} frames ({per_sec}, ETA: {eta})",
+		// Workers failing cancels this child token, which leaves the caller's
+		// token (and any other jobs sharing it) alone.
+		let cancel = self.cancel.child_token();
+		// The audio is decoded separately, alongside the video.
+		let audio = match scrubber.audio.clone() {
+			Some(analyzer) => {
+				let input = self.input.clone();
+				let cancel = cancel.clone();
+				Some(
+					thread::Builder::new()
+						.name("audio analyzer".to_owned())
+						.spawn(move || analyzer.analyze(&input, &cancel))
+						.wrap_err("failed to spawn audio analyzer thread")?,
 				)
-				.unwrap()
-				.with_key("pos", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{}", HumanCount(state.pos())).unwrap()
-				})
-				.with_key("len", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{}", HumanCount(state.len().unwrap())).unwrap()
-				})
-				.with_key("per_sec", |state: &ProgressState, w: &mut dyn Write| {
-					write!(w, "{:.1} fps", state.per_sec().round() as u64).unwrap()
-				}),
-			);
-			while !DONE_PROCESSING.load(Ordering::Relaxed) {
-				let frames_processed = FRAMES_PROCESSED.load(Ordering::Relaxed) as u64;
-				if frames_processed >= total_frames {
-					DONE_PROCESSING.store(true, Ordering::Relaxed);
-					break;
-				}
-				progress_bar.set_position(frames_processed);
-				std::thread::yield_now();
 			}
-			progress_bar.finish();
-		})
-		.wrap_err("failed to spawn progress bar thread")?;
+			None => None,
+		};
+		let workers = frame::cpu::spawn_threads(
+			scrubber.threads,
+			&detector,
+			frame_receiver,
+			result_sender,
+			self.observer.clone(),
+			&cancel,
+		)
+		.wrap_err("failed to setup cpu worker threads")?;
 
-	frame::send_frames(&mut capture, frame_sender)
-		.wrap_err("failed to send frames to worker threads")?;
+		let mut timeline = self.timeline.clone();
+		let mut scene_cuts = Vec::new();
+		let queue_stats = frame::send_frames(
+			&mut self.capture,
+			frame_sender,
+			&scrubber.preprocessor,
+			self.observer.as_ref(),
+			&cancel,
+			|index, millis, stats| {
+				timeline.push_millis(millis);
+				if stats.is_scene_cut(scrubber.scene_threshold) {
+					scene_cuts.push(index);
+				}
+			},
+		);
+		if queue_stats.is_err() {
+			cancel.cancel();
+		}
+		let frames_scanned = workers.join().wrap_err("cpu worker thread errored")?;
+		let queue_stats = queue_stats.wrap_err("failed to send frames to worker threads")?;
 
-	drop(frame_receiver);
-	drop(result_sender);
-	let _ = progress_thread.join();
-	println!("finished scanning video");
-	DONE_PROCESSING.store(true, Ordering::Relaxed);
+		let mut detections = collector
+			.join()
+			.map_err(|_| eyre!("detection collector thread panicked"))?;
+		detections.sort_unstable_by_key(|(index, _)| *index);
+		let matched_frames = detections
+			.iter()
+			.filter(|(_, detection)| detection.matched)
+			.map(|(index, _)| *index)
+			.collect::<Vec<_>>();
+		let best_scale = detections
+			.iter()
+			.map(|(_, detection)| detection)
+			.filter(|detection| detection.matched)
+			.max_by(|a, b| a.pos_score.total_cmp(&b.pos_score))
+			.and_then(|best| best.scale);
+		let audio = match audio {
+			Some(audio) => audio
+				.join()
+				.map_err(|_| eyre!("audio analyzer thread panicked"))?
+				.wrap_err("failed to analyze audio")?,
+			None => AudioAnalysis::default(),
+		};
+		let flagged_frames = scrubber.run_rules.apply(&detections, self.fps);
+
+		self.observer
+			.on_progress(ProgressEvent::Phase(Phase::Segmenting));
+		let snap = scrubber.snap_window.map(|window| SceneSnap {
+			cuts: &scene_cuts,
+			window,
+		});
+		// Ranges are padded by the labels of the templates that matched, not
+		// those of frames that only sustained or bridged a run.
+		let frame_label = |index: usize| {
+			detections
+				.binary_search_by_key(&index, |(index, _)| *index)
+				.ok()
+				.map(|position| &detections[position].1)
+				.filter(|detection| detection.matched)
+				.and_then(|detection| detection.pos_label.as_deref())
+		};
+		let mut flagged_ranges = segments::frames_to_segments(
+			&scrubber.padding,
+			&timeline,
+			&flagged_frames,
+			frame_label,
+			snap,
+			SegmentMode::Keep,
+		);
+		let segments = match scrubber.mode {
+			// A highlight reel shouldn't be padded out with silences and jingles.
+			SegmentMode::Keep => flagged_ranges,
+			// Audio ranges are already sample accurate, so unlike the flagged
+			// frames they're removed without any padding.
+			SegmentMode::Remove => {
+				flagged_ranges.extend(
+					audio
+						.ranges()
+						.into_iter()
+						.map(|range| range.rescale(audio::TIME_BASE, timeline.time_base())),
+				);
+				segments::remove_ranges(&timeline, flagged_ranges)
+			}
+		};
+		let scores = detections
+			.into_iter()
+			.map(|(index, detection)| {
+				let timestamp = timeline
+					.frame_start(index)
+					.map_or(f64::NAN, |pts| timeline.seconds(pts));
+				ScoreRecord::new(index, timestamp, detection)
+			})
+			.collect();
 
-	let mut exceeding_frames = exceeding_frames.lock();
-	exceeding_frames.sort(); // Sort the frames in ascending order
+		Ok(ScrubResult {
+			stats: ScrubStats {
+				total_frames: self.total_frames,
+				frames_decoded: queue_stats.frames_decoded,
+				frames_scanned,
+				frames_matched: matched_frames.len(),
+				elapsed: start.elapsed(),
+				queue: queue_stats,
+			},
+			matched_frames,
+			best_scale,
+			scene_cuts,
+			audio,
+			scores,
+			segments,
+			timeline,
+			cancelled: self.cancel.is_cancelled(),
+		})
+	}
 
-	let segments = segments::frames_to_segments(&args, &capture, &exceeding_frames)
-		.wrap_err("failed to convert frames to time ranges")?;
+	/// Splices the segments from a previous [`ScrubJob::scan`] into a single
+	/// output video.
+	pub fn splice<P: AsRef<Path>>(&self, result: &ScrubResult, output: P) -> Result<()> {
+		self.observer
+			.on_progress(ProgressEvent::Phase(Phase::Splicing));
+		video::splice_video(&self.input, output, &result.segments, &result.timeline)
+			.wrap_err("failed to splice segments into single video")?;
+		self.observer
+			.on_progress(ProgressEvent::Phase(Phase::Finished));
+		Ok(())
+	}
 
-	for (idx, (start, end)) in segments.iter().copied().enumerate() {
-		println!("segment #{idx}: {start:.1}s -> {end:.1}s");
+	/// Runs the full pipeline, scanning the video and splicing the result into
+	/// the given output.
+	pub fn run<P: AsRef<Path>>(&mut self, output: P) -> Result<ScrubResult> {
+		let result = self.scan()?;
+		self.splice(&result, output)?;
+		Ok(result)
 	}
+}
 
-	let percentage_exceeding = (exceeding_frames.len() as f64 / total_frames) * 100.0;
-	println!(
-		"found {} (out of {}) exceeding frames ({:.2}%)",
-		exceeding_frames.len(),
-		total_frames,
-		percentage_exceeding
-	);
+/// The outcome of scanning a video.
+#[derive(Debug, Clone)]
+pub struct ScrubResult {
+	/// The indices of every frame that matched, in ascending order.
+	pub matched_frames: Vec<usize>,
+	/// The template scale factor of the strongest match, if the detector
+	/// reports one.
+	pub best_scale: Option<f64>,
+	/// The indices of the frames that start a new shot, in ascending order.
+	pub scene_cuts: Vec<usize>,
+	/// The silences and reference clips found in the audio, which are removed
+	/// along with the matched frames unless only those are being kept.
+	pub audio: AudioAnalysis,
+	/// The scores of every scanned frame, in ascending order.
+	pub scores: Vec<ScoreRecord>,
+	/// The time ranges of the video that will be kept, in the time base of
+	/// `timeline`.
+	pub segments: Vec<TimeRange>,
+	/// The timestamps of every decoded frame.
+	pub timeline: Timeline,
+	pub stats: ScrubStats,
+	/// Whether the scan was cancelled before reaching the end of the video,
+	/// in which case only the frames scanned so far are accounted for.
+	pub cancelled: bool,
+}
 
-	println!("splicing video");
-	video::splice_video(&args, &segments)
-		.wrap_err("failed to splice segments into single video")?;
-	println!("finished splicing video");
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubStats {
+	/// The frame count reported by the video container.
+	pub total_frames: usize,
+	/// The amount of frames decoded and queued for the workers.
+	pub frames_decoded: usize,
+	/// The amount of frames actually matched by the workers.
+	pub frames_scanned: usize,
+	pub frames_matched: usize,
+	pub elapsed: Duration,
+	pub queue: QueueStats,
+}
 
-	Ok(())
+impl ScrubStats {
+	/// The percentage (0-100) of scanned frames that matched.
+	pub fn match_percentage(&self) -> f64 {
+		if self.frames_scanned == 0 {
+			return 0.0;
+		}
+		(self.frames_matched as f64 / self.frames_scanned as f64) * 100.0
+	}
 }
@@ -1,82 +1,947 @@
-use color_eyre::eyre::{Result, WrapErr};
-use opencv::videoio::{VideoCapture, VideoCaptureTraitConst, CAP_PROP_FPS, CAP_PROP_FRAME_COUNT};
+use crate::{
+	detect::Detection,
+	timeline::{TimeRange, Timeline},
+};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use std::{collections::HashMap, str::FromStr};
 
-pub type TimeRange = (f64, f64);
+/// A length of video, either as a frame count or in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameSpan {
+	Frames(usize),
+	Seconds(f64),
+}
+
+impl FrameSpan {
+	/// The length in frames, rounded to the nearest frame.
+	pub fn frames(&self, fps: f64) -> usize {
+		match *self {
+			Self::Frames(frames) => frames,
+			Self::Seconds(seconds) => (seconds * fps).round().max(0.0) as usize,
+		}
+	}
+}
+
+impl FromStr for FrameSpan {
+	type Err = color_eyre::eyre::Report;
+
+	/// Parses a frame count such as `12`, or seconds such as `0.5s`.
+	fn from_str(s: &str) -> Result<Self> {
+		let s = s.trim();
+		match s.strip_suffix('s') {
+			Some(seconds) => f64::from_str(seconds.trim())
+				.ok()
+				.filter(|seconds| *seconds >= 0.0)
+				.map(Self::Seconds),
+			None => usize::from_str(s).ok().map(Self::Frames),
+		}
+		.ok_or_else(|| eyre!("invalid length '{s}', expected frames (12) or seconds (0.5s)"))
+	}
+}
+
+/// How far to extend a flagged range of frames on either side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Padding {
+	/// Added before the first flagged frame.
+	pub lead_in: FrameSpan,
+	/// Added after the last flagged frame.
+	pub lead_out: FrameSpan,
+}
+
+impl Default for Padding {
+	fn default() -> Self {
+		Self::symmetric(FrameSpan::Frames(0))
+	}
+}
+
+impl Padding {
+	pub fn new(lead_in: FrameSpan, lead_out: FrameSpan) -> Self {
+		Self { lead_in, lead_out }
+	}
+
+	/// The same padding on both sides.
+	pub fn symmetric(span: FrameSpan) -> Self {
+		Self::new(span, span)
+	}
+}
+
+impl FromStr for Padding {
+	type Err = color_eyre::eyre::Report;
+
+	/// Parses `LEAD_IN:LEAD_OUT` such as `0.2s:2s`, or a single length for
+	/// both sides.
+	fn from_str(s: &str) -> Result<Self> {
+		match s.split_once(':') {
+			Some((lead_in, lead_out)) => Ok(Self::new(
+				FrameSpan::from_str(lead_in).wrap_err("invalid lead-in")?,
+				FrameSpan::from_str(lead_out).wrap_err("invalid lead-out")?,
+			)),
+			None => FrameSpan::from_str(s).map(Self::symmetric),
+		}
+	}
+}
+
+/// The padding of flagged ranges, optionally depending on the label of the
+/// template that flagged them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PaddingRules {
+	/// The padding of ranges without a label, or with a label that has no
+	/// padding of its own.
+	pub default: Padding,
+	pub labels: HashMap<String, Padding>,
+}
+
+impl PaddingRules {
+	/// The same padding for every range, regardless of labels.
+	pub fn new(default: Padding) -> Self {
+		Self {
+			default,
+			labels: HashMap::new(),
+		}
+	}
+
+	/// The padding of a range flagged by the template with the given label.
+	pub fn get(&self, label: Option<&str>) -> Padding {
+		label
+			.and_then(|label| self.labels.get(label))
+			.copied()
+			.unwrap_or(self.default)
+	}
+}
 
-pub fn frames_to_segments<ExceedingFrames>(
-	padding: f64,
-	capture: &VideoCapture,
+/// Rules for turning per-frame detections into runs of frames to remove.
+///
+/// Runs only start on matched frames, but continue through frames that
+/// [sustain](Detection::sustains) them, which is up to the detector's own exit
+/// threshold. The defaults neither drop nor join any runs.
+#[derive(Debug, Clone, Copy)]
+pub struct RunRules {
+	/// Runs shorter than this are dropped as false positives.
+	pub min_run: FrameSpan,
+	/// Gaps between runs of at most this length are bridged, joining the runs.
+	pub max_gap: FrameSpan,
+}
+
+impl Default for RunRules {
+	fn default() -> Self {
+		Self {
+			min_run: FrameSpan::Frames(1),
+			max_gap: FrameSpan::Frames(0),
+		}
+	}
+}
+
+impl RunRules {
+	/// Applies the rules to the detections of every scanned frame, sorted by
+	/// index, returning the indices of the frames to remove.
+	pub fn apply(&self, detections: &[(usize, Detection)], fps: f64) -> Vec<usize> {
+		let min_run = self.min_run.frames(fps).max(1);
+		let max_gap = self.max_gap.frames(fps);
+
+		// Hysteresis: runs only start on a match, but end once a frame no
+		// longer sustains them.
+		let mut runs: Vec<(usize, usize)> = Vec::new();
+		let mut current: Option<(usize, usize)> = None;
+		for &(index, ref detection) in detections {
+			let keep = match current {
+				Some((_, last)) if last + 1 == index => detection.matched || detection.sustains,
+				_ => detection.matched,
+			};
+			current = match (current, keep) {
+				(Some((first, last)), true) if last + 1 == index => Some((first, index)),
+				(run, true) => {
+					runs.extend(run);
+					Some((index, index))
+				}
+				(run, false) => {
+					runs.extend(run);
+					None
+				}
+			};
+		}
+		runs.extend(current);
+
+		// Bridging a gap flags every frame in it, so it must not undo a veto.
+		let vetoed = detections
+			.iter()
+			.filter(|(_, detection)| detection.vetoed)
+			.map(|(index, _)| *index)
+			.collect::<Vec<_>>();
+		let vetoed_between = |after: usize, before: usize| {
+			let next = vetoed.partition_point(|&index| index <= after);
+			vetoed.get(next).is_some_and(|&index| index < before)
+		};
+		let mut bridged: Vec<(usize, usize)> = Vec::with_capacity(runs.len());
+		for (first, last) in runs {
+			match bridged.last_mut() {
+				Some((_, previous))
+					if first - *previous - 1 <= max_gap && !vetoed_between(*previous, first) =>
+				{
+					*previous = last;
+				}
+				_ => bridged.push((first, last)),
+			}
+		}
+
+		bridged
+			.into_iter()
+			.filter(|(first, last)| last - first + 1 >= min_run)
+			.flat_map(|(first, last)| first..=last)
+			.collect()
+	}
+}
+
+/// What happens to the parts of the video where frames were flagged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SegmentMode {
+	/// Cut the flagged parts out, keeping everything else.
+	#[default]
+	Remove,
+	/// Keep only the flagged parts, such as for a highlights reel.
+	Keep,
+}
+
+impl FromStr for SegmentMode {
+	type Err = color_eyre::eyre::Report;
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s.trim().to_ascii_lowercase().as_str() {
+			"remove" | "scrub" => Ok(Self::Remove),
+			"keep" | "extract" => Ok(Self::Keep),
+			_ => Err(eyre!("unknown segment mode '{s}', expected remove or keep")),
+		}
+	}
+}
+
+/// Moves the edges of removal ranges to the nearest scene cut, rather than
+/// padding them by a fixed amount.
+#[derive(Debug, Clone, Copy)]
+pub struct SceneSnap<'a> {
+	/// The indices of the frames that start a new shot, in ascending order.
+	pub cuts: &'a [usize],
+	/// How many seconds away from a range's edge a scene cut may be. Edges
+	/// without a scene cut this close fall back to the fixed padding.
+	pub window: f64,
+}
+
+impl SceneSnap<'_> {
+	/// The start of the scene cut closest to the given timestamp, if one is
+	/// within the window.
+	fn nearest(&self, timeline: &Timeline, pts: i64) -> Option<i64> {
+		let window = timeline.ticks(self.window);
+		let cut_pts = |i: usize| self.cuts.get(i).and_then(|&cut| timeline.frame_start(cut));
+		let after = self
+			.cuts
+			.partition_point(|&cut| timeline.frame_start(cut).is_some_and(|cut| cut < pts));
+		let before = after.checked_sub(1).and_then(cut_pts);
+		[before, cut_pts(after)]
+			.into_iter()
+			.flatten()
+			.min_by_key(|cut| cut.abs_diff(pts))
+			.filter(|cut| cut.abs_diff(pts) <= window.unsigned_abs())
+	}
+}
+
+/// Builds the time ranges to keep, given the sorted indices of the flagged
+/// frames. Depending on the mode, those are either the padded ranges around
+/// the flagged frames, or everything in between them.
+///
+/// `frame_label` gives the label of the template that matched a frame, if
+/// any, which picks the range's padding. It should return `None` for frames
+/// that are only flagged because they sustained or bridged a run, so that a
+/// template that never matched can't pad the range. A range flagged by
+/// several labels is padded by the furthest reaching of their paddings on each
+/// side, and a range without any labels gets the default padding.
+///
+/// Every input produces a result: in [`SegmentMode::Remove`], no frames keeps
+/// the whole video, and padding or snapping never reaches outside of the
+/// video's bounds. The returned ranges are sorted, non-empty and don't touch
+/// each other.
+pub fn frames_to_segments<'a, ExceedingFrames, FrameLabel>(
+	padding: &PaddingRules,
+	timeline: &Timeline,
 	exceeding_frames: ExceedingFrames,
-) -> Result<Vec<TimeRange>>
+	frame_label: FrameLabel,
+	snap: Option<SceneSnap>,
+	mode: SegmentMode,
+) -> Vec<TimeRange>
 where
 	ExceedingFrames: AsRef<[usize]>,
+	FrameLabel: Fn(usize) -> Option<&'a str>,
 {
-	frames_to_segments_impl(padding, capture, exceeding_frames.as_ref())
+	frames_to_segments_impl(
+		padding,
+		timeline,
+		exceeding_frames.as_ref(),
+		&frame_label,
+		snap,
+		mode,
+	)
 }
 
-fn frames_to_segments_impl(
-	padding: f64,
-	capture: &VideoCapture,
+fn frames_to_segments_impl<'a>(
+	padding: &PaddingRules,
+	timeline: &Timeline,
 	exceeding_frames: &[usize],
-) -> Result<Vec<TimeRange>> {
-	let fps = capture
-		.get(CAP_PROP_FPS)
-		.wrap_err("failed to read fps property from video")?;
-	let mut time_ranges = Vec::new();
-	let mut start_frame = exceeding_frames[0];
-	let mut end_frame = exceeding_frames[0];
-
-	for i in 1..exceeding_frames.len() {
-		if exceeding_frames[i] - exceeding_frames[i - 1] > 1 {
-			let start_time = (start_frame as f64 / fps) - padding;
-			let end_time = (end_frame as f64 / fps) + padding;
-			time_ranges.push((start_time, end_time));
+	frame_label: &dyn Fn(usize) -> Option<&'a str>,
+	snap: Option<SceneSnap>,
+	mode: SegmentMode,
+) -> Vec<TimeRange> {
+	let (video_start, video_end) = (timeline.start(), timeline.end());
+	// Frame based padding counts real frames, so it follows variable frame
+	// rates. Frames past either end of the video pad to the video's bounds.
+	let pad_start = |start_frame: usize, start: i64, span: FrameSpan| match span {
+		FrameSpan::Frames(frames) => match start_frame.checked_sub(frames) {
+			Some(frame) => timeline.frame_start(frame).unwrap_or(video_end),
+			None => video_start,
+		},
+		FrameSpan::Seconds(seconds) => start.saturating_sub(timeline.ticks(seconds).max(0)),
+	};
+	let pad_end = |end_frame: usize, end: i64, span: FrameSpan| match span {
+		FrameSpan::Frames(frames) => end_frame
+			.checked_add(frames)
+			.and_then(|frame| timeline.frame_end(frame))
+			.unwrap_or(video_end),
+		FrameSpan::Seconds(seconds) => end.saturating_add(timeline.ticks(seconds).max(0)),
+	};
+	let run_padding = |start_frame: usize, end_frame: usize| -> Vec<Padding> {
+		if padding.labels.is_empty() {
+			return vec![padding.default];
+		}
+		let mut paddings = Vec::new();
+		for label in (start_frame..=end_frame).filter_map(frame_label) {
+			let label_padding = padding.get(Some(label));
+			if !paddings.contains(&label_padding) {
+				paddings.push(label_padding);
+			}
+		}
+		if paddings.is_empty() {
+			paddings.push(padding.default);
+		}
+		paddings
+	};
+	let to_range = |start_frame: usize, end_frame: usize| -> TimeRange {
+		let start = timeline.frame_start(start_frame).unwrap_or(video_end);
+		// The range ends where the frame after the last match starts.
+		let end = timeline.frame_end(end_frame).unwrap_or(video_end);
+		let paddings = run_padding(start_frame, end_frame);
+		let padded_start = paddings
+			.iter()
+			.map(|padding| pad_start(start_frame, start, padding.lead_in))
+			.min()
+			.unwrap_or(start);
+		let padded_end = paddings
+			.iter()
+			.map(|padding| pad_end(end_frame, end, padding.lead_out))
+			.max()
+			.unwrap_or(end);
+		let mut start_cut = snap.and_then(|snap| snap.nearest(timeline, start));
+		let mut end_cut = snap.and_then(|snap| snap.nearest(timeline, end));
+		// Snapping both edges inward can cross them over on short ranges.
+		if let (Some(start), Some(end)) = (start_cut, end_cut) {
+			if start >= end {
+				(start_cut, end_cut) = (None, None);
+			}
+		}
+		TimeRange::new(
+			start_cut
+				.unwrap_or(padded_start)
+				.clamp(video_start, video_end),
+			end_cut.unwrap_or(padded_end).clamp(video_start, video_end),
+		)
+	};
 
-			start_frame = exceeding_frames[i];
+	// Group the frames into runs of consecutive frames. Duplicates are folded
+	// into their run, and out of order frames start a run of their own. Frames
+	// past the end of the timeline are dropped first, as padding them would
+	// flag the end of the video.
+	let mut runs: Vec<(usize, usize)> = Vec::new();
+	for &index in exceeding_frames
+		.iter()
+		.filter(|&&index| index < timeline.len())
+	{
+		match runs.last_mut() {
+			Some((first, last)) if (*first..=last.saturating_add(1)).contains(&index) => {
+				*last = (*last).max(index);
+			}
+			_ => runs.push((index, index)),
 		}
-		end_frame = exceeding_frames[i];
 	}
 
-	// Add the last range if it was continuous
-	let start_time = (start_frame as f64 / fps) - padding;
-	let end_time = (end_frame as f64 / fps) + padding;
-	time_ranges.push((start_time, end_time));
+	let flagged_ranges = runs
+		.into_iter()
+		.map(|(start_frame, end_frame)| to_range(start_frame, end_frame))
+		.collect::<Vec<_>>();
+	match mode {
+		SegmentMode::Keep => merge_ranges(flagged_ranges),
+		SegmentMode::Remove => remove_ranges(timeline, flagged_ranges),
+	}
+}
+
+/// Sorts the ranges and joins the ones that overlap or touch, dropping empty
+/// ones.
+fn merge_ranges(mut ranges: Vec<TimeRange>) -> Vec<TimeRange> {
+	ranges.retain(|range| range.start < range.end);
+	ranges.sort_unstable_by_key(|range| range.start);
+	let mut merged_ranges: Vec<TimeRange> = Vec::with_capacity(ranges.len());
+	for range in ranges {
+		match merged_ranges.last_mut() {
+			Some(previous) if range.start <= previous.end => {
+				previous.end = previous.end.max(range.end);
+			}
+			_ => merged_ranges.push(range),
+		}
+	}
+	merged_ranges
+}
 
-	let mut merged_time_ranges = Vec::new();
-	let mut current_range = time_ranges[0];
+/// Builds the time ranges to keep when removing the given ranges from the
+/// video, as is, without any padding. The ranges may be unsorted and overlap,
+/// and are clamped to the video's bounds.
+///
+/// This is for ranges that are already exact, such as audio, which can be
+/// combined with the padded ranges of [`frames_to_segments`] in
+/// [`SegmentMode::Keep`].
+pub fn remove_ranges(timeline: &Timeline, ranges: Vec<TimeRange>) -> Vec<TimeRange> {
+	let (video_start, video_end) = (timeline.start(), timeline.end());
+	let merged_ranges = merge_ranges(
+		ranges
+			.into_iter()
+			.map(|range| {
+				TimeRange::new(
+					range.start.clamp(video_start, video_end),
+					range.end.clamp(video_start, video_end),
+				)
+			})
+			.collect(),
+	);
 
-	for &(start, end) in &time_ranges[1..] {
-		if start <= current_range.1 {
-			current_range.1 = current_range.1.max(end);
-		} else {
-			merged_time_ranges.push(current_range);
-			current_range = (start, end);
+	// Keep everything between the removed ranges.
+	let mut kept_ranges = Vec::with_capacity(merged_ranges.len() + 1);
+	let mut previous_end = video_start;
+	for range in merged_ranges {
+		if range.start > previous_end {
+			kept_ranges.push(TimeRange::new(previous_end, range.start));
 		}
+		previous_end = range.end;
+	}
+	if video_end > previous_end {
+		kept_ranges.push(TimeRange::new(previous_end, video_end));
 	}
 
-	merged_time_ranges.push(current_range);
+	kept_ranges
+}
 
-	let mut non_matching_time_ranges = Vec::new();
-	let mut previous_end_time = 0.0;
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ffmpeg_next::Rational;
+	use proptest::prelude::*;
 
-	for (start_time, end_time) in merged_time_ranges.iter().copied() {
-		let gap_duration = start_time - previous_end_time;
-		if gap_duration > 0.0 {
-			non_matching_time_ranges.push((previous_end_time, start_time));
+	/// A millisecond time base timeline with the given frame durations.
+	fn timeline(start: i64, end: Option<i64>, durations: &[i64]) -> Timeline {
+		let mut timeline = Timeline::new(Rational(1, 1000), start, end, 25.0);
+		let mut pts = start;
+		for duration in durations {
+			timeline.push(pts);
+			pts += duration;
 		}
-		previous_end_time = end_time;
+		timeline
+	}
+
+	fn seconds(padding: f64) -> PaddingRules {
+		PaddingRules::new(Padding::symmetric(FrameSpan::Seconds(padding)))
+	}
+
+	fn check_invariants(timeline: &Timeline, segments: &[TimeRange]) {
+		for segment in segments {
+			assert!(segment.start < segment.end, "empty segment {segment:?}");
+			assert!(
+				segment.start >= timeline.start(),
+				"{segment:?} starts too early"
+			);
+			assert!(segment.end <= timeline.end(), "{segment:?} ends too late");
+		}
+		for pair in segments.windows(2) {
+			assert!(pair[0].end < pair[1].start, "{pair:?} overlap or touch");
+		}
+	}
+
+	fn frame_range(timeline: &Timeline, index: usize) -> TimeRange {
+		TimeRange::new(
+			timeline.frame_start(index).unwrap(),
+			timeline.frame_end(index).unwrap(),
+		)
+	}
+
+	#[test]
+	fn no_frames_keeps_everything() {
+		let timeline = timeline(100, None, &[40; 10]);
+		let segments = frames_to_segments(
+			&seconds(1.0),
+			&timeline,
+			Vec::<usize>::new(),
+			|_| None,
+			None,
+			SegmentMode::Remove,
+		);
+		assert_eq!(segments, vec![TimeRange::new(100, 500)]);
 	}
 
-	// Calculate the total video duration
-	let total_frames = capture
-		.get(CAP_PROP_FRAME_COUNT)
-		.wrap_err("failed to get frame count property from video")?;
-	let total_duration = total_frames / fps;
+	#[test]
+	fn empty_video_has_no_segments() {
+		let timeline = timeline(0, None, &[]);
+		assert!(frames_to_segments(
+			&seconds(1.0),
+			&timeline,
+			Vec::<usize>::new(),
+			|_| None,
+			None,
+			SegmentMode::Remove
+		)
+		.is_empty());
+		assert!(frames_to_segments(
+			&seconds(1.0),
+			&timeline,
+			[0_usize, 1, 2],
+			|_| None,
+			None,
+			SegmentMode::Remove
+		)
+		.is_empty());
+	}
+
+	#[test]
+	fn all_frames_removes_everything() {
+		let timeline = timeline(0, Some(400), &[40; 10]);
+		let frames = (0..10).collect::<Vec<_>>();
+		assert!(frames_to_segments(
+			&seconds(0.0),
+			&timeline,
+			frames,
+			|_| None,
+			None,
+			SegmentMode::Remove
+		)
+		.is_empty());
+	}
+
+	#[test]
+	fn single_frame() {
+		let timeline = timeline(0, None, &[40]);
+		assert!(frames_to_segments(
+			&seconds(0.0),
+			&timeline,
+			[0_usize],
+			|_| None,
+			None,
+			SegmentMode::Remove
+		)
+		.is_empty());
+		assert_eq!(
+			frames_to_segments(
+				&seconds(0.0),
+				&timeline,
+				Vec::<usize>::new(),
+				|_| None,
+				None,
+				SegmentMode::Remove
+			),
+			vec![TimeRange::new(0, 40)]
+		);
+	}
+
+	#[test]
+	fn padding_is_clamped_to_the_video() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let segments = frames_to_segments(
+			&seconds(0.5),
+			&timeline,
+			[0_usize, 1, 98, 99],
+			|_| None,
+			None,
+			SegmentMode::Remove,
+		);
+		assert_eq!(segments, vec![TimeRange::new(580, 3420)]);
+	}
+
+	#[test]
+	fn keep_mode_returns_the_padded_matches() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let segments = frames_to_segments(
+			&seconds(0.1),
+			&timeline,
+			[0_usize, 1, 2, 50, 52, 99],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![
+			TimeRange::new(0, 220),
+			TimeRange::new(1900, 2220),
+			TimeRange::new(3860, 4000),
+		]);
+		assert!(frames_to_segments(
+			&seconds(0.1),
+			&timeline,
+			Vec::<usize>::new(),
+			|_| None,
+			None,
+			SegmentMode::Keep
+		)
+		.is_empty());
+	}
+
+	#[test]
+	fn frames_past_the_end_are_ignored() {
+		let timeline = timeline(0, None, &[40; 10]);
+		let segments = frames_to_segments(
+			&seconds(0.0),
+			&timeline,
+			[5_usize, 20, 21],
+			|_| None,
+			None,
+			SegmentMode::Remove,
+		);
+		assert_eq!(segments, vec![
+			TimeRange::new(0, 200),
+			TimeRange::new(240, 400)
+		]);
+
+		let segments = frames_to_segments(
+			&seconds(0.04),
+			&timeline,
+			[5_usize, 20, 21],
+			|_| None,
+			None,
+			SegmentMode::Remove,
+		);
+		assert_eq!(segments, vec![
+			TimeRange::new(0, 160),
+			TimeRange::new(280, 400)
+		]);
+	}
+
+	#[test]
+	fn removed_ranges_are_not_padded() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let mut flagged = frames_to_segments(
+			&seconds(0.2),
+			&timeline,
+			[50_usize],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		flagged.extend([
+			TimeRange::new(1000, 1500),
+			TimeRange::new(3950, 5000),
+			TimeRange::new(1900, 2050),
+		]);
+		assert_eq!(remove_ranges(&timeline, flagged), vec![
+			TimeRange::new(0, 1000),
+			TimeRange::new(1500, 1800),
+			TimeRange::new(2240, 3950),
+		]);
+	}
+
+	#[test]
+	fn asymmetric_padding() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let padding =
+			PaddingRules::new(Padding::new(FrameSpan::Seconds(0.2), FrameSpan::Frames(3)));
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[50_usize, 51],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![TimeRange::new(1800, 2200)]);
+	}
 
-	// Include the last non-matching time range if needed
-	if total_duration - previous_end_time > 0.0 {
-		non_matching_time_ranges.push((previous_end_time, total_duration));
+	#[test]
+	fn frame_padding_follows_variable_frame_rates() {
+		let timeline = timeline(0, None, &[10, 10, 100, 100, 10, 10]);
+		let padding = PaddingRules::new(Padding::symmetric(FrameSpan::Frames(1)));
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[2_usize, 3],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![TimeRange::new(10, 230)]);
 	}
 
-	Ok(non_matching_time_ranges)
+	#[test]
+	fn label_padding() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let mut padding = PaddingRules::new(Padding::symmetric(FrameSpan::Frames(0)));
+		padding.labels.insert(
+			"ad".to_owned(),
+			Padding::new(FrameSpan::Frames(0), FrameSpan::Seconds(1.0)),
+		);
+		padding.labels.insert(
+			"badge".to_owned(),
+			Padding::new(FrameSpan::Frames(2), FrameSpan::Frames(0)),
+		);
+		let label = |index: usize| match index {
+			10 => Some("ad"),
+			11 => Some("badge"),
+			50 => Some("unknown"),
+			_ => None,
+		};
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[10_usize, 11, 50, 80],
+			label,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![
+			TimeRange::new(320, 1480),
+			TimeRange::new(2000, 2040),
+			TimeRange::new(3200, 3240),
+		]);
+	}
+
+	#[test]
+	fn only_labelled_frames_pick_the_padding() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let mut padding = PaddingRules::new(Padding::symmetric(FrameSpan::Frames(2)));
+		padding
+			.labels
+			.insert("ad".to_owned(), Padding::symmetric(FrameSpan::Frames(0)));
+		// Frames 21 and 22 only continued the run the ad started.
+		let label = |index: usize| (index == 20).then_some("ad");
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[20_usize, 21, 22, 50],
+			label,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![
+			TimeRange::new(800, 920),
+			TimeRange::new(1920, 2120),
+		]);
+	}
+
+	#[test]
+	fn huge_frame_padding_reaches_the_end() {
+		let timeline = timeline(0, None, &[40; 100]);
+		let padding = PaddingRules::new(Padding::new(
+			FrameSpan::Frames(0),
+			FrameSpan::Frames(usize::MAX),
+		));
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[10_usize],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![TimeRange::new(400, 4000)]);
+
+		// The padded end frame is exactly the largest index there can be.
+		let segments = frames_to_segments(
+			&padding,
+			&timeline,
+			[0_usize],
+			|_| None,
+			None,
+			SegmentMode::Keep,
+		);
+		assert_eq!(segments, vec![TimeRange::new(0, 4000)]);
+	}
+
+	#[test]
+	fn padding_parses() {
+		assert_eq!(
+			Padding::from_str("0.2s:2s").unwrap(),
+			Padding::new(FrameSpan::Seconds(0.2), FrameSpan::Seconds(2.0))
+		);
+		assert_eq!(
+			Padding::from_str("5").unwrap(),
+			Padding::symmetric(FrameSpan::Frames(5))
+		);
+		assert!(Padding::from_str("1:2:3").is_err());
+		assert!(Padding::from_str("-1s").is_err());
+	}
+
+	/// Consecutive detections starting at frame 0, from a string where `M` is a
+	/// match, `s` sustains a run, `v` was vetoed, and anything else is neither.
+	fn detections(frames: &str) -> Vec<(usize, Detection)> {
+		frames
+			.chars()
+			.enumerate()
+			.map(|(index, frame)| {
+				(index, Detection {
+					matched: frame == 'M',
+					sustains: frame == 's',
+					vetoed: frame == 'v',
+					..Detection::default()
+				})
+			})
+			.collect()
+	}
+
+	#[test]
+	fn default_run_rules_keep_every_match() {
+		let rules = RunRules::default();
+		assert_eq!(rules.apply(&detections("M.M.s.M"), 25.0), vec![0, 2, 6]);
+	}
+
+	#[test]
+	fn runs_continue_through_sustaining_frames() {
+		let rules = RunRules::default();
+		assert_eq!(rules.apply(&detections("sMss.sM"), 25.0), vec![1, 2, 3, 6]);
+	}
+
+	#[test]
+	fn runs_need_consecutive_frames() {
+		// Frame 3 wasn't scanned, so frame 4 can't continue the run.
+		let mut detections = detections("Mss");
+		detections.push((4, Detection {
+			sustains: true,
+			..Detection::default()
+		}));
+		assert_eq!(RunRules::default().apply(&detections, 25.0), vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn short_runs_are_dropped() {
+		let rules = RunRules {
+			min_run: FrameSpan::Frames(3),
+			..RunRules::default()
+		};
+		assert_eq!(rules.apply(&detections("MM.MMM.Ms.M"), 25.0), vec![3, 4, 5]);
+
+		let rules = RunRules {
+			min_run: FrameSpan::Seconds(0.1),
+			..RunRules::default()
+		};
+		assert_eq!(rules.apply(&detections("M.MMM"), 25.0), vec![2, 3, 4]);
+	}
+
+	#[test]
+	fn small_gaps_are_bridged() {
+		let rules = RunRules {
+			max_gap: FrameSpan::Frames(2),
+			..RunRules::default()
+		};
+		assert_eq!(rules.apply(&detections("M..M...Ms"), 25.0), vec![
+			0, 1, 2, 3, 7, 8
+		]);
+	}
+
+	#[test]
+	fn gaps_with_vetoed_frames_are_not_bridged() {
+		let rules = RunRules {
+			max_gap: FrameSpan::Frames(3),
+			..RunRules::default()
+		};
+		assert_eq!(rules.apply(&detections("M.vM..M"), 25.0), vec![
+			0, 3, 4, 5, 6
+		]);
+	}
+
+	#[test]
+	fn bridged_runs_count_towards_the_minimum() {
+		let rules = RunRules {
+			min_run: FrameSpan::Frames(4),
+			max_gap: FrameSpan::Frames(1),
+			..RunRules::default()
+		};
+		assert_eq!(rules.apply(&detections("M.MM..MM"), 25.0), vec![0, 1, 2, 3]);
+	}
+
+	proptest! {
+		#[test]
+		fn segments_are_well_formed(
+			start in -1000_i64..1000,
+			end in proptest::option::of(-1000_i64..10_000),
+			durations in proptest::collection::vec(1_i64..100, 0..200),
+			frames in proptest::collection::vec(0_usize..250, 0..100),
+			padding in (0_usize..20, 0.0_f64..5.0, any::<bool>(), any::<bool>()),
+			cuts in proptest::collection::vec(0_usize..250, 0..20),
+			window in proptest::option::of(0.0_f64..2.0),
+			keep in any::<bool>(),
+		) {
+			let timeline = timeline(start, end, &durations);
+			let (frame_count, seconds, frame_lead_in, frame_lead_out) = padding;
+			let span = |frames| if frames {
+				FrameSpan::Frames(frame_count)
+			} else {
+				FrameSpan::Seconds(seconds)
+			};
+			let mut padding = PaddingRules::new(Padding::new(span(frame_lead_in), span(frame_lead_out)));
+			padding.labels.insert("odd".to_owned(), Padding::new(span(frame_lead_out), span(frame_lead_in)));
+			let label = |index: usize| (index % 2 == 1).then_some("odd");
+			let mode = if keep { SegmentMode::Keep } else { SegmentMode::Remove };
+			let mut cuts = cuts;
+			cuts.sort_unstable();
+			let snap = window.map(|window| SceneSnap { cuts: &cuts, window });
+			let segments = frames_to_segments(&padding, &timeline, &frames, label, snap, mode);
+			check_invariants(&timeline, &segments);
+		}
+
+		#[test]
+		fn unpadded_segments_keep_exactly_the_other_frames(
+			durations in proptest::collection::vec(1_i64..100, 1..200),
+			removed in proptest::collection::vec(any::<bool>(), 200),
+		) {
+			let timeline = timeline(0, None, &durations);
+			let frames = (0..timeline.len())
+				.filter(|&index| removed[index])
+				.collect::<Vec<_>>();
+			let segments =
+				frames_to_segments(&seconds(0.0), &timeline, &frames, |_| None, None, SegmentMode::Remove);
+			check_invariants(&timeline, &segments);
+			for index in 0..timeline.len() {
+				let frame = frame_range(&timeline, index);
+				let kept = segments
+					.iter()
+					.any(|segment| segment.start <= frame.start && frame.end <= segment.end);
+				let overlaps = segments
+					.iter()
+					.any(|segment| segment.start < frame.end && frame.start < segment.end);
+				prop_assert_eq!(kept, !removed[index], "frame {} is {:?}", index, frame);
+				prop_assert_eq!(overlaps, kept, "frame {} is partially kept", index);
+			}
+		}
+
+		#[test]
+		fn keep_and_remove_partition_the_video(
+			durations in proptest::collection::vec(1_i64..100, 0..200),
+			frames in proptest::collection::vec(0_usize..250, 0..100),
+			padding in 0.0_f64..5.0,
+		) {
+			let timeline = timeline(0, None, &durations);
+			let padding = seconds(padding);
+			let mut segments =
+				frames_to_segments(&padding, &timeline, &frames, |_| None, None, SegmentMode::Keep);
+			segments.extend(frames_to_segments(
+				&padding,
+				&timeline,
+				&frames,
+				|_| None,
+				None,
+				SegmentMode::Remove,
+			));
+			segments.sort_unstable_by_key(|segment| segment.start);
+			let mut previous_end = timeline.start();
+			for segment in segments {
+				prop_assert_eq!(segment.start, previous_end);
+				previous_end = segment.end;
+			}
+			prop_assert_eq!(previous_end, timeline.end());
+		}
+	}
 }
@@ -1,21 +1,209 @@
-use color_eyre::eyre::{ContextCompat, Result, WrapErr};
-use opencv::{core::Mat, imgcodecs};
-use std::path::Path;
+use crate::{fixup::Preprocessor, frame::cpu::MatchMethod};
+use color_eyre::eyre::{eyre, ContextCompat, Result, WrapErr};
+use opencv::{
+	core::{self, Mat, MatTraitConst, Rect, CV_16U, CV_8U},
+	imgcodecs::{self, IMREAD_UNCHANGED},
+	imgproc,
+};
+use serde::Deserialize;
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
 use walkdir::WalkDir;
 
-pub fn load_image(path: &Path, flags: i32) -> Result<Mat> {
+/// Alpha values above this are treated as part of the template, anything more
+/// transparent is left out of matching.
+const ALPHA_THRESHOLD: f64 = 127.0;
+
+#[derive(Clone)]
+pub struct Template {
+	pub image: Mat,
+	/// Which pixels of the image should be matched, taken from the alpha
+	/// channel of the source image if it has one.
+	pub mask: Option<Mat>,
+	/// A human readable name, defaulting to the image's file name.
+	pub label: String,
+	/// Overrides the detector's threshold for this template.
+	pub threshold: Option<f64>,
+	/// Overrides the detector's region of interest for this template.
+	pub bounds: Option<Rect>,
+	/// Overrides the detector's matching method for this template.
+	pub method: Option<MatchMethod>,
+}
+
+/// A list of templates with their own settings, loaded from TOML or JSON.
+///
+/// ```toml
+/// [[template]]
+/// path = "badges/sponsored.png"
+/// threshold = 0.85
+/// roi = [1600, 0, 320, 180]
+/// method = "ccorr_normed"
+/// label = "sponsored"
+/// ```
+///
+/// Paths are relative to the manifest and may point to a directory, in which
+/// case the settings apply to every image in it.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+	#[serde(rename = "template", alias = "templates", default)]
+	templates: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+	path: PathBuf,
+	label: Option<String>,
+	threshold: Option<f64>,
+	/// x, y, width, height
+	roi: Option<[i32; 4]>,
+	method: Option<String>,
+}
+
+fn is_manifest(path: &Path) -> bool {
+	matches!(
+		path.extension().and_then(|ext| ext.to_str()),
+		Some("toml" | "json")
+	)
+}
+
+pub fn load_manifest(path: &Path, preprocessor: &Preprocessor) -> Result<Vec<Template>> {
+	let contents = fs::read_to_string(path)
+		.wrap_err_with(|| format!("failed to read manifest at {}", path.display()))?;
+	let manifest: Manifest = match path.extension().and_then(|ext| ext.to_str()) {
+		Some("json") => serde_json::from_str(&contents).wrap_err("invalid json manifest")?,
+		_ => toml::from_str(&contents).wrap_err("invalid toml manifest")?,
+	};
+	let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+	let mut templates = Vec::new();
+	for entry in manifest.templates {
+		let entry_path = base.join(&entry.path);
+		let method = entry
+			.method
+			.as_deref()
+			.map(MatchMethod::from_str)
+			.transpose()
+			.wrap_err_with(|| format!("invalid method for {}", entry_path.display()))?;
+		let bounds = entry
+			.roi
+			.map(|[x, y, width, height]| Rect::new(x, y, width, height));
+		for mut template in load(&entry_path, preprocessor)
+			.wrap_err_with(|| format!("failed to load template(s) from {}", entry_path.display()))?
+		{
+			if let Some(label) = &entry.label {
+				template.label.clone_from(label);
+			}
+			template.threshold = entry.threshold.or(template.threshold);
+			template.bounds = bounds.or(template.bounds);
+			template.method = method.or(template.method);
+			templates.push(template);
+		}
+	}
+	Ok(templates)
+}
+
+/// Builds a binary mask out of the image's alpha channel, or returns `None`
+/// if the image has no alpha channel or is fully opaque.
+fn alpha_mask(image: &Mat) -> Result<Option<Mat>> {
+	if image.channels() != 4 {
+		return Ok(None);
+	}
+	let mut alpha = Mat::default();
+	core::extract_channel(image, &mut alpha, 3).wrap_err("failed to extract alpha channel")?;
+	let mut min_alpha = 0.0;
+	core::min_max_loc(
+		&alpha,
+		Some(&mut min_alpha),
+		None,
+		None,
+		None,
+		&core::no_array(),
+	)
+	.wrap_err("failed to find minimum alpha value")?;
+	if min_alpha > ALPHA_THRESHOLD {
+		return Ok(None);
+	}
+	let mut mask = Mat::default();
+	imgproc::threshold(
+		&alpha,
+		&mut mask,
+		ALPHA_THRESHOLD,
+		255.0,
+		imgproc::THRESH_BINARY,
+	)
+	.wrap_err("failed to threshold alpha channel")?;
+	Ok(Some(mask))
+}
+
+/// Converts an image read with [`IMREAD_UNCHANGED`] to 8 bits per channel, the
+/// same depth decoded video frames have. 16-bit images, such as some PNGs, are
+/// scaled down, and anything else is rejected.
+fn to_8bit(image: &Mat) -> Result<Mat> {
+	match image.depth() {
+		CV_8U => Ok(image.clone()),
+		CV_16U => {
+			let mut converted = Mat::default();
+			image
+				.convert_to(&mut converted, CV_8U, 1.0 / 257.0, 0.0)
+				.wrap_err("failed to convert 16-bit image")?;
+			Ok(converted)
+		}
+		depth => Err(eyre!(
+			"unsupported image depth {depth}, expected 8 or 16 bits per channel"
+		)),
+	}
+}
+
+/// Converts an image read with [`IMREAD_UNCHANGED`] into BGR, the same
+/// layout decoded video frames have.
+fn to_bgr(image: &Mat) -> Result<Mat> {
+	let code = match image.channels() {
+		3 => return Ok(image.clone()),
+		1 => imgproc::COLOR_GRAY2BGR,
+		4 => imgproc::COLOR_BGRA2BGR,
+		channels => return Err(eyre!("unsupported amount of channels: {channels}")),
+	};
+	let mut converted = Mat::default();
+	imgproc::cvt_color(image, &mut converted, code, 0).wrap_err("failed to convert image")?;
+	Ok(converted)
+}
+
+pub fn load_image(path: &Path, preprocessor: &Preprocessor) -> Result<Template> {
 	let path = path
 		.to_str()
 		.wrap_err("invalid path cannot be represented as a str")?;
-	let img = imgcodecs::imread(path, flags)
+	let img = imgcodecs::imread(path, IMREAD_UNCHANGED)
 		.wrap_err_with(|| format!("failed to read image from {}", path))?;
-	crate::fixup::fixup_frame_2(&img, false)
-		.wrap_err_with(|| format!("failed to fixup image from {}", path))
+	let img = to_8bit(&img).wrap_err_with(|| format!("failed to convert image from {}", path))?;
+	let mask = alpha_mask(&img).wrap_err_with(|| format!("failed to read mask from {}", path))?;
+	let img = to_bgr(&img).wrap_err_with(|| format!("failed to convert image from {}", path))?;
+	let image = crate::fixup::fixup_frame_2(&img, preprocessor)
+		.wrap_err_with(|| format!("failed to fixup image from {}", path))?;
+	let label = Path::new(path)
+		.file_stem()
+		.map(|stem| stem.to_string_lossy().into_owned())
+		.unwrap_or_else(|| path.to_owned());
+	Ok(Template {
+		image,
+		mask,
+		label,
+		threshold: None,
+		bounds: None,
+		method: None,
+	})
 }
 
-pub fn load(path: &Path, flags: i32) -> Result<Vec<Mat>> {
+pub fn load(path: &Path, preprocessor: &Preprocessor) -> Result<Vec<Template>> {
 	if path.is_file() {
-		return load_image(path, flags).map(|template| vec![template]);
+		if is_manifest(path) {
+			return load_manifest(path, preprocessor);
+		}
+		return load_image(path, preprocessor).map(|template| vec![template]);
 	}
 	let mut templates = Vec::new();
 	for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
@@ -26,19 +214,22 @@ pub fn load(path: &Path, flags: i32) -> Result<Vec<Mat>> {
 		{
 			continue;
 		}
-		let image = load_image(entry_path, flags)
+		let image = load_image(entry_path, preprocessor)
 			.wrap_err_with(|| format!("failed to load image at {}", entry_path.display()))?;
 		templates.push(image);
 	}
 	Ok(templates)
 }
 
-pub fn load_multi<P: AsRef<Path>>(paths: &[P], flags: i32) -> Result<Vec<Mat>> {
+pub fn load_multi<P: AsRef<Path>>(
+	paths: &[P],
+	preprocessor: &Preprocessor,
+) -> Result<Vec<Template>> {
 	Ok(paths
 		.iter()
 		.map(|path| {
 			let path = path.as_ref();
-			load(path, flags)
+			load(path, preprocessor)
 				.wrap_err_with(|| format!("failed to load template(s) from {}", path.display()))
 		})
 		.collect::<Result<Vec<_>>>()
@@ -0,0 +1,270 @@
+use ffmpeg_next::{Rational, Rescale};
+use std::ops::Range;
+
+/// A half-open range of timestamps, `start..end`, in some stream's time base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeRange {
+	pub start: i64,
+	pub end: i64,
+}
+
+impl TimeRange {
+	#[inline]
+	pub fn new(start: i64, end: i64) -> Self {
+		Self { start, end }
+	}
+
+	#[inline]
+	pub fn duration(&self) -> i64 {
+		self.end - self.start
+	}
+
+	/// Converts the range from one time base to another, rounding to the
+	/// nearest tick.
+	pub fn rescale(&self, from: Rational, to: Rational) -> Self {
+		Self {
+			start: self.start.rescale(from, to),
+			end: self.end.rescale(from, to),
+		}
+	}
+
+	/// The range in seconds, for display.
+	pub fn seconds(&self, time_base: Rational) -> (f64, f64) {
+		let time_base = f64::from(time_base);
+		(self.start as f64 * time_base, self.end as f64 * time_base)
+	}
+}
+
+/// The presentation timestamps of every decoded frame of a video stream, in
+/// the stream's time base.
+///
+/// Frame indices start at 0. A frame lasts until the next frame's timestamp,
+/// which keeps variable frame rate recordings accurate.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+	time_base: Rational,
+	/// The timestamp the stream starts at, which isn't always 0.
+	start: i64,
+	/// The timestamp the stream ends at, if the container reports it.
+	end: Option<i64>,
+	/// The nominal duration of a single frame, for when the real one can't be
+	/// known.
+	frame_duration: i64,
+	pts: Vec<i64>,
+}
+
+impl Timeline {
+	pub fn new(time_base: Rational, start: i64, end: Option<i64>, fps: f64) -> Self {
+		let frame_duration = if fps > 0.0 && time_base.numerator() > 0 {
+			(f64::from(time_base.denominator()) / (fps * f64::from(time_base.numerator())))
+				.round()
+				.max(1.0) as i64
+		} else {
+			1
+		};
+		Self {
+			time_base,
+			start,
+			end,
+			frame_duration,
+			pts: Vec::new(),
+		}
+	}
+
+	/// Records the timestamp of the next decoded frame, given as milliseconds
+	/// since the start of the stream.
+	pub fn push_millis(&mut self, millis: f64) {
+		let pts = if millis.is_finite() && millis >= 0.0 {
+			self.start + self.ticks(millis / 1000.0)
+		} else {
+			i64::MIN
+		};
+		self.push(pts);
+	}
+
+	/// Records the timestamp of the next decoded frame. Timestamps that are
+	/// missing or would go backwards are replaced by assuming a constant frame
+	/// rate since the previous frame.
+	pub fn push(&mut self, pts: i64) {
+		let pts = match self.pts.last() {
+			Some(&last) if pts <= last => last + self.frame_duration,
+			None if pts < self.start => self.start,
+			_ => pts,
+		};
+		self.pts.push(pts);
+	}
+
+	#[inline]
+	pub fn time_base(&self) -> Rational {
+		self.time_base
+	}
+
+	#[inline]
+	pub fn start(&self) -> i64 {
+		self.start
+	}
+
+	/// The timestamp the stream ends at, falling back to the end of the last
+	/// decoded frame.
+	pub fn end(&self) -> i64 {
+		let last_end = self
+			.pts
+			.last()
+			.map_or(self.start, |&last| last + self.frame_duration);
+		match self.end {
+			Some(end) => end.max(last_end),
+			None => last_end,
+		}
+	}
+
+	/// The amount of frames with a known timestamp.
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.pts.len()
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.pts.is_empty()
+	}
+
+	/// The timestamp a frame starts at.
+	#[inline]
+	pub fn frame_start(&self, index: usize) -> Option<i64> {
+		self.pts.get(index).copied()
+	}
+
+	/// The timestamp a frame ends at, which is when the next frame starts.
+	pub fn frame_end(&self, index: usize) -> Option<i64> {
+		let next = index.checked_add(1)?;
+		match self.pts.get(next) {
+			Some(&next) => Some(next),
+			None if next == self.pts.len() => Some(self.end()),
+			None => None,
+		}
+	}
+
+	/// The frames that start inside the range.
+	pub fn frames_in(&self, range: TimeRange) -> Range<usize> {
+		let first = self.pts.partition_point(|&pts| pts < range.start);
+		let last = self.pts.partition_point(|&pts| pts < range.end);
+		first..last.max(first)
+	}
+
+	/// Converts a duration in seconds to ticks of the time base.
+	pub fn ticks(&self, seconds: f64) -> i64 {
+		(seconds * f64::from(self.time_base.denominator()) / f64::from(self.time_base.numerator()))
+			.round() as i64
+	}
+
+	/// How many seconds into the stream a timestamp is, for display.
+	pub fn seconds(&self, pts: i64) -> f64 {
+		(pts - self.start) as f64 * f64::from(self.time_base)
+	}
+
+	/// The range in seconds since the start of the stream, for display.
+	pub fn range_seconds(&self, range: TimeRange) -> (f64, f64) {
+		(self.seconds(range.start), self.seconds(range.end))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A millisecond time base timeline at 25 fps, so frames nominally last
+	/// 40 ticks.
+	fn timeline(start: i64, end: Option<i64>, pts: &[i64]) -> Timeline {
+		let mut timeline = Timeline::new(Rational(1, 1000), start, end, 25.0);
+		for &pts in pts {
+			timeline.push(pts);
+		}
+		timeline
+	}
+
+	fn frame_starts(timeline: &Timeline) -> Vec<i64> {
+		(0..timeline.len())
+			.map(|index| timeline.frame_start(index).unwrap())
+			.collect()
+	}
+
+	#[test]
+	fn backwards_timestamps_are_repaired() {
+		let timeline = timeline(0, None, &[0, 40, 40, 20, 400]);
+		assert_eq!(frame_starts(&timeline), vec![0, 40, 80, 120, 400]);
+		assert_eq!(timeline.end(), 440);
+	}
+
+	#[test]
+	fn missing_timestamps_are_repaired() {
+		let mut timeline = timeline(0, None, &[]);
+		timeline.push_millis(0.0);
+		timeline.push_millis(f64::NAN);
+		timeline.push_millis(-1.0);
+		timeline.push_millis(200.0);
+		timeline.push_millis(f64::INFINITY);
+		assert_eq!(frame_starts(&timeline), vec![0, 40, 80, 200, 240]);
+	}
+
+	#[test]
+	fn non_zero_start() {
+		let mut timeline = timeline(1000, Some(2000), &[900]);
+		timeline.push_millis(40.0);
+		timeline.push(1100);
+		assert_eq!(frame_starts(&timeline), vec![1000, 1040, 1100]);
+		assert_eq!(timeline.start(), 1000);
+		// The container's end wins over the last frame's.
+		assert_eq!(timeline.end(), 2000);
+		assert_eq!(timeline.frame_end(2), Some(2000));
+		assert_eq!(timeline.seconds(1040), 0.04);
+		assert_eq!(
+			timeline.range_seconds(TimeRange::new(1000, 2000)),
+			(0.0, 1.0)
+		);
+	}
+
+	#[test]
+	fn empty_timeline() {
+		let timeline = timeline(500, None, &[]);
+		assert!(timeline.is_empty());
+		assert_eq!(timeline.end(), 500);
+		assert_eq!(timeline.frame_start(0), None);
+		assert_eq!(timeline.frame_end(0), None);
+		assert_eq!(timeline.frames_in(TimeRange::new(0, 1000)), 0..0);
+	}
+
+	#[test]
+	fn frame_end() {
+		let timeline = timeline(0, None, &[0, 40, 100]);
+		assert_eq!(timeline.frame_end(0), Some(40));
+		assert_eq!(timeline.frame_end(1), Some(100));
+		// The last frame lasts a nominal frame duration.
+		assert_eq!(timeline.frame_end(2), Some(140));
+		assert_eq!(timeline.frame_end(3), None);
+		assert_eq!(timeline.frame_end(usize::MAX), None);
+	}
+
+	#[test]
+	fn frames_in_range_edges() {
+		let timeline = timeline(0, None, &[0, 40, 80, 120]);
+		let frames_in = |start, end| timeline.frames_in(TimeRange::new(start, end));
+		assert_eq!(frames_in(0, 40), 0..1);
+		assert_eq!(frames_in(40, 80), 1..2);
+		// Only frames that start inside the range count.
+		assert_eq!(frames_in(1, 41), 1..2);
+		assert_eq!(frames_in(41, 80), 2..2);
+		assert_eq!(frames_in(80, 80), 2..2);
+		assert_eq!(frames_in(100, 60), 3..3);
+		assert_eq!(frames_in(-100, 0), 0..0);
+		assert_eq!(frames_in(-100, 1000), 0..4);
+		assert_eq!(frames_in(121, 1000), 4..4);
+	}
+
+	#[test]
+	fn frame_duration_falls_back_without_fps() {
+		let mut timeline = Timeline::new(Rational(1, 1000), 0, None, 0.0);
+		timeline.push(0);
+		timeline.push(0);
+		assert_eq!(frame_starts(&timeline), vec![0, 1]);
+	}
+}
@@ -1,22 +1,65 @@
-use crate::segments::TimeRange;
+use crate::timeline::{TimeRange, Timeline};
 use color_eyre::eyre::{ContextCompat, Result, WrapErr};
+use ffmpeg::{Rational, Rescale};
 use ffmpeg_next as ffmpeg;
 use std::path::Path;
 
+/// Reads the time base and bounds of the best video stream of a file, and
+/// creates an empty [`Timeline`] for it.
+pub fn probe_timeline<P: AsRef<Path>>(input: P, fps: f64) -> Result<Timeline> {
+	let input = input.as_ref();
+	ffmpeg::init().wrap_err("failed to initialize ffmpeg")?;
+	let ictx = ffmpeg::format::input(&input)
+		.wrap_err_with(|| format!("failed to open input file at {}", input.display()))?;
+	let stream = ictx
+		.streams()
+		.best(ffmpeg::media::Type::Video)
+		.wrap_err("input has no video stream")?;
+	let time_base = stream.time_base();
+	let start = match stream.start_time() {
+		start if start == ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+		start => start,
+	};
+	let end = if stream.duration() > 0 {
+		Some(start + stream.duration())
+	} else if ictx.duration() > 0 {
+		// The container's duration is in microseconds.
+		Some(
+			start
+				+ ictx
+					.duration()
+					.rescale(Rational(1, ffmpeg::ffi::AV_TIME_BASE), time_base),
+		)
+	} else {
+		None
+	};
+	Ok(Timeline::new(time_base, start, end, fps))
+}
+
+/// Copies the given segments of the input into the output, back to back,
+/// starting at the start of the timeline. The segments are in the timeline's
+/// time base.
 pub fn splice_video<Input, Output, Segments>(
 	input: Input,
 	output: Output,
 	segments: Segments,
+	timeline: &Timeline,
 ) -> Result<()>
 where
 	Input: AsRef<Path>,
 	Output: AsRef<Path>,
 	Segments: AsRef<[TimeRange]>,
 {
-	splice_video_impl(input.as_ref(), output.as_ref(), segments.as_ref())
+	splice_video_impl(input.as_ref(), output.as_ref(), segments.as_ref(), timeline)
 }
 
-fn splice_video_impl(input: &Path, output: &Path, segments: &[TimeRange]) -> Result<()> {
+fn splice_video_impl(
+	input: &Path,
+	output: &Path,
+	segments: &[TimeRange],
+	timeline: &Timeline,
+) -> Result<()> {
+	let time_base = timeline.time_base();
 	ffmpeg::init().wrap_err("failed to initialize ffmpeg")?;
 
 	let mut ictx = ffmpeg::format::input(&input)
@@ -39,38 +82,40 @@ fn splice_video_impl(input: &Path, output: &Path, segments: &[TimeRange]) -> Res
 	octx.write_header()
 		.wrap_err("failed to write output header")?;
 
-	let mut total_offset_pts = 0;
-	let mut total_offset_dts = 0;
+	// How much time has been cut out so far, in the segments' time base. The
+	// output starts where the first kept segment does, which isn't the start
+	// of the video when its beginning is cut.
+	let mut removed = segments
+		.first()
+		.map_or(0, |first| first.start - timeline.start());
 
 	let mut segment_idx = 0;
 	let mut current_segment = segments.first().copied();
 
 	for (stream, mut packet) in ictx.packets() {
-		let (start_time, end_time) = match current_segment {
-			Some(times) => times,
-			None => break,
+		let Some(segment) = current_segment else {
+			break;
 		};
-		let time_base = f64::from(stream.time_base());
+		let stream_time_base = stream.time_base();
 
 		let pts = packet.pts().wrap_err("invalid pts")?;
 		let dts = packet.dts().wrap_err("invalid dts")?;
 
-		let packet_pts = (pts as f64) * time_base;
-		let packet_dts = (dts as f64) * time_base;
+		let range = segment.rescale(time_base, stream_time_base);
 
 		// Skip packets that are before the current segment.
-		if packet_pts < start_time || packet_dts < start_time {
+		if pts < range.start || dts < range.start {
 			continue;
 		}
 
-		if packet_pts >= end_time || packet_dts >= end_time {
+		if pts >= range.end || dts >= range.end {
 			segment_idx += 1;
 			current_segment = segments.get(segment_idx).copied();
 			match current_segment {
-				Some((next_start, next_end)) => {
-					total_offset_pts += ((next_start - end_time) / time_base) as i64;
-					total_offset_dts = total_offset_pts;
-					if packet_pts < next_start || packet_pts >= next_end {
+				Some(next) => {
+					removed += next.start - segment.end;
+					let next = next.rescale(time_base, stream_time_base);
+					if pts < next.start || pts >= next.end {
 						continue;
 					}
 				}
@@ -79,10 +124,9 @@ fn splice_video_impl(input: &Path, output: &Path, segments: &[TimeRange]) -> Res
 		}
 
 		// Adjust the PTS and DTS for continuous playback.
-		let new_pts = pts - total_offset_pts;
-		let new_dts = dts - total_offset_dts;
-		packet.set_pts(Some(new_pts));
-		packet.set_dts(Some(new_dts));
+		let offset = removed.rescale(time_base, stream_time_base);
+		packet.set_pts(Some(pts - offset));
+		packet.set_dts(Some(dts - offset));
 
 		packet
 			.write_interleaved(&mut octx)